@@ -2,6 +2,11 @@
 
 set -ex
 
+# The TLS backend is rustls unless another one is given in $FEATURES
+if [ -n "$FEATURES" ]; then
+    CARGO_FLAGS="--no-default-features --features $FEATURES"
+fi
+
 main() {
     local src=$(pwd) \
           stage=
@@ -18,7 +23,7 @@ main() {
     test -f Cargo.lock || cargo generate-lockfile
 
     # TODO Update this to build the artifacts that matter to you
-    RUSTFLAGS="$RUSTFLAGS -C link-arg=-s" cross build --target $TARGET --release
+    RUSTFLAGS="$RUSTFLAGS -C link-arg=-s" cross build --target $TARGET $CARGO_FLAGS --release
 
     # TODO Update this to package the right artifacts
     cp target/$TARGET/release/yadd $stage/
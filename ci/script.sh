@@ -2,17 +2,22 @@
 
 set -ex
 
+# The TLS backend is rustls unless another one is given in $FEATURES
+if [ -n "$FEATURES" ]; then
+    CARGO_FLAGS="--no-default-features --features $FEATURES"
+fi
+
 # TODO This is the "test phase", tweak it as you see fit
 main() {
-    cross build --target $TARGET
-    cross build --target $TARGET --release
+    cross build --target $TARGET $CARGO_FLAGS
+    cross build --target $TARGET $CARGO_FLAGS --release
 
     if [ ! -z $DISABLE_TESTS ]; then
         return
     fi
 
-    cross test --target $TARGET
-    cross test --target $TARGET --release
+    cross test --target $TARGET $CARGO_FLAGS
+    cross test --target $TARGET $CARGO_FLAGS --release
 
     # cross run --target $TARGET
     # cross run --target $TARGET --release
@@ -0,0 +1,219 @@
+//! A small HTTP API for operating yadd at runtime.
+//!
+//! * `GET /upstreams` lists the upstreams with their state
+//! * `POST /upstreams/<name>/enable` and `POST /upstreams/<name>/disable`
+//! * `POST /rules/requests/<index>/enable`, `POST /rules/requests/<index>/disable`
+//!   and the same for `responses`. Rules are indexed from 0 in the config file order.
+//! * `POST /reload` reloads the config file
+//! * `GET /query?name=<name>&type=<type>` sends a test query to every upstream chosen
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::config::{AdminListener, Config};
+use crate::dispatcher::{Dispatcher, SharedDispatcher};
+use crate::dnstap::Dnstap;
+use crate::querylog::QueryLog;
+use crate::{STDERR, STDOUT};
+
+use failure::{err_msg, Error};
+use hyper::server::conn::AddrIncoming;
+use hyper::server::Builder;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use slog::{error, info};
+use tokio::prelude::*;
+use trust_dns::op::Query;
+use trust_dns_proto::rr::record_type::RecordType;
+use trust_dns_proto::rr::Name;
+
+type ResponseFuture = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// Everything needed to rebuild the dispatcher
+#[derive(Clone)]
+pub struct Admin {
+    dispatcher: SharedDispatcher,
+    config_path: String,
+    query_log: Option<Arc<QueryLog>>,
+    dnstap: Option<Arc<Dnstap>>,
+}
+
+impl Admin {
+    pub fn new(
+        dispatcher: SharedDispatcher,
+        config_path: String,
+        query_log: Option<Arc<QueryLog>>,
+        dnstap: Option<Arc<Dnstap>>,
+    ) -> Self {
+        Admin {
+            dispatcher,
+            config_path,
+            query_log,
+            dnstap,
+        }
+    }
+
+    /// Only the upstreams and the rules are reloaded.
+    /// Other settings take effect after restarting.
+    fn reload(&self) -> Result<(), Error> {
+        let config = Config::load(&self.config_path)?;
+        let dispatcher = Dispatcher::new(config, self.query_log.clone(), self.dnstap.clone())?;
+        self.dispatcher.replace(dispatcher);
+        info!(STDOUT, "Config reloaded from {}", self.config_path);
+        Ok(())
+    }
+}
+
+pub enum Listener {
+    Tcp(Builder<AddrIncoming>),
+    #[cfg(unix)]
+    Unix(tokio_uds::UnixListener),
+}
+
+pub fn bind(listener: &AdminListener) -> Result<Listener, Error> {
+    match listener {
+        AdminListener::Tcp(addr) => Ok(Listener::Tcp(Server::try_bind(addr)?)),
+        AdminListener::Unix(path) => bind_unix(path),
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str) -> Result<Listener, Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // Remove the socket left by the last run
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(Listener::Unix(tokio_uds::UnixListener::bind(path)?))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &str) -> Result<Listener, Error> {
+    Err(err_msg("Unix sockets are not supported on this platform"))
+}
+
+pub fn serve(listener: Listener, admin: Admin) -> Box<Future<Item = (), Error = ()> + Send> {
+    let new_service = move || {
+        let admin = admin.clone();
+        service_fn(move |req| handle(&admin, req))
+    };
+    let server: Box<Future<Item = (), Error = hyper::Error> + Send> = match listener {
+        Listener::Tcp(builder) => Box::new(builder.serve(new_service)),
+        #[cfg(unix)]
+        Listener::Unix(listener) => {
+            Box::new(Server::builder(listener.incoming()).serve(new_service))
+        }
+    };
+    Box::new(server.map_err(|e| error!(STDERR, "Admin server error: {}", e)))
+}
+
+fn handle(admin: &Admin, req: Request<Body>) -> ResponseFuture {
+    let path: Vec<_> = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let dispatcher = admin.dispatcher.get();
+    let resp = match (req.method(), path.as_slice()) {
+        (&Method::GET, ["upstreams"]) => reply(StatusCode::OK, &dispatcher.upstreams()),
+        (&Method::POST, ["upstreams", name, action]) => switch(action, |enabled| {
+            dispatcher.set_upstream_enabled(name, enabled)
+        }),
+        (&Method::POST, ["rules", "requests", index, action]) => switch(action, |enabled| {
+            dispatcher.set_request_rule_enabled(index.parse()?, enabled)
+        }),
+        (&Method::POST, ["rules", "responses", index, action]) => switch(action, |enabled| {
+            dispatcher.set_response_rule_enabled(index.parse()?, enabled)
+        }),
+        (&Method::POST, ["reload"]) => match admin.reload() {
+            Ok(()) => empty(StatusCode::NO_CONTENT),
+            Err(e) => failure(StatusCode::BAD_REQUEST, e),
+        },
+        (&Method::GET, ["query"]) => match test_query(req.uri().query().unwrap_or_default()) {
+            Ok(query) => {
+                return Box::new(dispatcher.test(query).then(|res| match res {
+                    Ok(entry) => Ok(reply(StatusCode::OK, &entry)),
+                    Err(()) => Ok(empty(StatusCode::INTERNAL_SERVER_ERROR)),
+                }));
+            }
+            Err(e) => failure(StatusCode::BAD_REQUEST, e),
+        },
+        (_, ["cache"]) | (_, ["cache", _]) => failure(
+            StatusCode::NOT_IMPLEMENTED,
+            err_msg("Responses are not cached by yadd"),
+        ),
+        _ => empty(StatusCode::NOT_FOUND),
+    };
+    Box::new(future::ok(resp))
+}
+
+fn switch<F>(action: &str, set: F) -> Response<Body>
+where
+    F: FnOnce(bool) -> Result<(), Error>,
+{
+    let enabled = match action {
+        "enable" => true,
+        "disable" => false,
+        _ => return empty(StatusCode::NOT_FOUND),
+    };
+    match set(enabled) {
+        Ok(()) => empty(StatusCode::NO_CONTENT),
+        Err(e) => failure(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// Parses `name=<name>&type=<type>`. The type defaults to A.
+fn test_query(params: &str) -> Result<Query, Error> {
+    let mut name = None;
+    let mut record_type = RecordType::A;
+    for param in params.split('&') {
+        let mut pair = param.splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some("name"), Some(value)) => name = Some(value),
+            (Some("type"), Some(value)) => record_type = RecordType::from_str(value)?,
+            _ => {}
+        }
+    }
+    let name = name.ok_or(err_msg("name is missing"))?;
+    // Queries from clients are always fully qualified
+    let name = if name.ends_with('.') {
+        Name::from_str(name)?
+    } else {
+        Name::from_str(&format!("{}.", name))?
+    };
+    Ok(Query::query(name, record_type))
+}
+
+fn reply<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    match serde_json::to_vec(body) {
+        Ok(body) => {
+            let mut resp = Response::new(Body::from(body));
+            *resp.status_mut() = status;
+            resp.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("application/json"),
+            );
+            resp
+        }
+        Err(e) => failure(StatusCode::INTERNAL_SERVER_ERROR, e.into()),
+    }
+}
+
+fn failure(status: StatusCode, e: Error) -> Response<Body> {
+    let body = json!({ "error": e.to_string() });
+    let mut resp = Response::new(Body::from(body.to_string()));
+    *resp.status_mut() = status;
+    resp
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = status;
+    resp
+}
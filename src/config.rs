@@ -4,22 +4,44 @@ use std::io::prelude::*;
 use std::io::BufReader;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::Duration;
 
 use crate::ip::IpRange;
+use crate::resolver::dnscrypt::Stamp;
+use crate::resolver::dnssec::{Status, TrustAnchor, ROOT_TRUST_ANCHOR};
+use crate::resolver::ecs::{ClientSubnet, EcsPolicy};
+use crate::resolver::proxy::Proxy;
+use crate::resolver::socket::SocketOptions;
+use crate::resolver::tcp::PoolOptions;
+use crate::resolver::tls::{Sni, TlsOptions};
 use crate::Transpose;
 
 use failure::{err_msg, Error};
 use ipnet::IpNet;
 use regex::RegexSet;
 use serde_derive::Deserialize;
+use slog::Level;
 use std::net::IpAddr;
 use trust_dns_proto::rr::record_type::RecordType;
+use trust_dns_proto::rr::Name;
 
 #[derive(Debug)]
 pub struct Config {
     pub bind: SocketAddr,
+    pub metrics: Option<SocketAddr>,
+    pub deadline: Option<Duration>,
+    pub admin: Option<AdminListener>,
     pub default_upstreams: Vec<String>,
+    pub default_priority: Option<Priority>,
+    pub health_check: Option<HealthCheck>,
+    pub query_log: Option<QueryLogOutput>,
+    pub dnstap: Option<DnstapOutput>,
+    pub dnssec: Option<Dnssec>,
+    pub rebind_protection: Option<RebindProtection>,
+    pub bogus_nxdomain: Option<BogusNxdomain>,
+    pub log: Log,
     pub upstreams: HashMap<String, Upstream>,
+    pub groups: HashMap<String, Group>,
     pub domains: HashMap<String, Domains>,
     pub ranges: HashMap<String, IpRange>,
     pub request_rules: Vec<RequestRule>,
@@ -29,27 +51,116 @@ pub struct Config {
 #[derive(Debug, Deserialize)]
 pub struct ConfigBuilder {
     bind: SocketAddr,
+    metrics: Option<SocketAddr>,
+    deadline: Option<u64>,
+    admin: Option<AdminConfig>,
+    prefer: Option<Vec<String>>,
+    #[serde(rename = "grace-period")]
+    grace_period: Option<u64>,
+    #[serde(rename = "health-check")]
+    health_check: Option<HealthCheckConfig>,
+    #[serde(rename = "query-log")]
+    query_log: Option<QueryLogConfig>,
+    dnstap: Option<DnstapConfig>,
+    dnssec: Option<DnssecConfig>,
+    #[serde(rename = "rebind-protection")]
+    rebind_protection: Option<RebindProtectionConfig>,
+    #[serde(rename = "bogus-nxdomain")]
+    bogus_nxdomain: Option<BogusNxdomainConfig>,
+    log: Option<LogConfig>,
     upstreams: HashMap<String, UpstreamConfig>,
+    groups: Option<HashMap<String, Group>>,
     domains: Option<HashMap<String, DomainsConf>>,
     ranges: Option<HashMap<String, IpRangeConf>>,
     requests: Option<Vec<RequestRuleConfig>>,
-    responses: Option<Vec<ResponseRule>>,
+    responses: Option<Vec<ResponseRuleConfig>>,
 }
 
 #[derive(Debug)]
-pub enum Upstream {
+pub struct Upstream {
+    pub kind: UpstreamKind,
+    pub options: UpstreamOptions,
+}
+
+#[derive(Debug)]
+pub enum UpstreamKind {
     TcpUpstream {
-        address: SocketAddr,
+        address: UpstreamAddress,
     },
     UdpUpstream {
         address: SocketAddr,
     },
     TlsUpstream {
-        address: SocketAddr,
+        address: UpstreamAddress,
         tls_host: String,
+        tls: TlsOptions,
+    },
+    DnscryptUpstream {
+        stamp: Stamp,
+    },
+}
+
+impl UpstreamKind {
+    /// The upstream used to resolve the hostname of this upstream
+    fn bootstrap(&self) -> Option<&str> {
+        match self {
+            UpstreamKind::TcpUpstream { address } | UpstreamKind::TlsUpstream { address, .. } => {
+                match address {
+                    UpstreamAddress::Name {
+                        bootstrap: Bootstrap::Upstream { name, .. },
+                        ..
+                    } => Some(name),
+                    _ => None,
+                }
+            }
+            UpstreamKind::UdpUpstream { .. } | UpstreamKind::DnscryptUpstream { .. } => None,
+        }
+    }
+}
+
+/// Only TCP and TLS upstreams can be given by hostnames
+#[derive(Debug)]
+pub enum UpstreamAddress {
+    Ip(SocketAddr),
+    Name {
+        host: Name,
+        port: u16,
+        bootstrap: Bootstrap,
     },
 }
 
+impl UpstreamAddress {
+    pub fn ip(&self) -> Option<SocketAddr> {
+        match self {
+            UpstreamAddress::Ip(addr) => Some(*addr),
+            UpstreamAddress::Name { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Bootstrap {
+    /// Resolves the hostname through an upstream given by an IP address every `interval`
+    Upstream {
+        name: String,
+        interval: Duration,
+    },
+    Static(Vec<IpAddr>),
+    /// Left to the proxy the upstream is connected through
+    Proxy,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, Error> {
+        let mut file = File::open(path)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+
+        let builder: ConfigBuilder = toml::from_str(&content)?;
+        builder.build()
+    }
+}
+
 impl ConfigBuilder {
     pub fn build(self) -> Result<Config, Error> {
         let mut default_upstreams = Vec::new();
@@ -65,12 +176,45 @@ impl ConfigBuilder {
             })
             .collect::<Result<HashMap<_, _>, Error>>()?;
 
+        for (name, upstream) in &upstreams {
+            if let Some(bootstrap) = upstream.kind.bootstrap() {
+                match upstreams.get(bootstrap) {
+                    None => {
+                        return Err(err_msg(format!(
+                            "Upstream {}: unknown bootstrap upstream {}",
+                            name, bootstrap
+                        )));
+                    }
+                    Some(b) if b.kind.bootstrap().is_some() => {
+                        return Err(err_msg(format!(
+                            "Upstream {}: bootstrap upstream {} must be given by an IP address",
+                            name, bootstrap
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
         if default_upstreams.is_empty() {
             return Err(err_msg(
                 "You must configure at least one default upstream server!",
             ));
         }
 
+        let groups = self.groups.unwrap_or_default();
+        for (name, group) in &groups {
+            if upstreams.contains_key(name) {
+                return Err(err_msg(format!(
+                    "Group {} has the same name as an upstream",
+                    name
+                )));
+            }
+            if let Some(u) = group.upstreams.iter().find(|u| !upstreams.contains_key(*u)) {
+                return Err(err_msg(format!("Group {}: unknown upstream {}", name, u)));
+            }
+        }
+
         let domains = self
             .domains
             .unwrap_or_default()
@@ -88,25 +232,470 @@ impl ConfigBuilder {
             })
             .collect::<Result<HashMap<_, _>, Error>>()?;
 
+        let rebind_protection =
+            Transpose::transpose(self.rebind_protection.map(|r| r.build(&domains)))?;
+        let bogus_nxdomain = Transpose::transpose(self.bogus_nxdomain.map(|b| b.build(&ranges)))?;
+
         let request_rules: Vec<RequestRule> = self
             .requests
             .unwrap_or_default()
             .into_iter()
+            .map(|r| r.build(&groups))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let response_rules: Vec<ResponseRule> = self
+            .responses
+            .unwrap_or_default()
+            .into_iter()
             .map(|r| r.build())
             .collect::<Result<Vec<_>, Error>>()?;
 
+        let default_priority =
+            Priority::build(self.prefer, self.grace_period, &default_upstreams, &groups)?;
+        let health_check = Transpose::transpose(self.health_check.map(|h| h.build()))?;
+        let query_log = Transpose::transpose(self.query_log.map(|q| q.build()))?;
+        let dnstap = Transpose::transpose(self.dnstap.map(|d| d.build()))?;
+        let dnssec = Transpose::transpose(self.dnssec.map(|d| d.build()))?;
+        if dnssec.is_none() && response_rules.iter().any(|r| r.dnssec.is_some()) {
+            return Err(err_msg(
+                "Response rules with dnssec require DNSSEC validation to be enabled in [dnssec]",
+            ));
+        }
+        let log = self.log.unwrap_or_default().build()?;
+        let admin = Transpose::transpose(self.admin.map(|a| a.build()))?;
+
         Ok(Config {
             bind: self.bind,
+            metrics: self.metrics,
+            deadline: self.deadline.map(Duration::from_millis),
+            admin,
             default_upstreams,
+            default_priority,
+            health_check,
+            query_log,
+            dnstap,
+            dnssec,
+            rebind_protection,
+            bogus_nxdomain,
+            log,
             upstreams,
+            groups,
             domains,
             ranges,
             request_rules,
-            response_rules: self.responses.unwrap_or_default(),
+            response_rules,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HealthCheckConfig {
+    interval: Option<u64>,
+    failures: Option<usize>,
+    domain: Option<String>,
+    #[serde(rename = "type")]
+    record_type: Option<String>,
+}
+
+impl HealthCheckConfig {
+    fn build(self) -> Result<HealthCheck, Error> {
+        let failures = self.failures.unwrap_or(3);
+        if failures == 0 {
+            return Err(err_msg("health-check.failures must be positive"));
+        }
+        let interval = self.interval.unwrap_or(30);
+        if interval == 0 {
+            return Err(err_msg("health-check.interval must be positive"));
+        }
+        let name = Name::from_str(self.domain.as_ref().map(|d| d.as_str()).unwrap_or("."))?;
+        let record_type = RecordType::from_str(
+            self.record_type
+                .as_ref()
+                .map(|t| t.as_str())
+                .unwrap_or("NS"),
+        )?;
+
+        Ok(HealthCheck {
+            interval: Duration::from_secs(interval),
+            failures,
+            name,
+            record_type,
+        })
+    }
+}
+
+/// Upstreams are probed periodically by querying `name`.
+/// An upstream is skipped after failing `failures` times in a row until it recovers.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub interval: Duration,
+    pub failures: usize,
+    pub name: Name,
+    pub record_type: RecordType,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryLogConfig {
+    output: String,
+    path: Option<String>,
+}
+
+impl QueryLogConfig {
+    fn build(self) -> Result<QueryLogOutput, Error> {
+        match self.output.as_str() {
+            "stdout" => Ok(QueryLogOutput::Stdout),
+            "syslog" => Ok(QueryLogOutput::Syslog),
+            "file" => {
+                let path = self.path.ok_or(err_msg("query-log.path is missing"))?;
+                Ok(QueryLogOutput::File(path))
+            }
+            output => Err(err_msg(format!("Invalid query log output: {}", output))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum QueryLogOutput {
+    Stdout,
+    Syslog,
+    File(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct DnssecConfig {
+    #[serde(rename = "trust-anchors")]
+    trust_anchors: Option<Vec<String>>,
+}
+
+impl DnssecConfig {
+    fn build(self) -> Result<Dnssec, Error> {
+        let trust_anchors = self
+            .trust_anchors
+            .unwrap_or_else(|| vec![ROOT_TRUST_ANCHOR.to_owned()])
+            .iter()
+            .map(|anchor| TrustAnchor::from_str(anchor))
+            .collect::<Result<Vec<_>, Error>>()?;
+        if trust_anchors.is_empty() {
+            return Err(err_msg("dnssec.trust-anchors must not be empty"));
+        }
+        Ok(Dnssec { trust_anchors })
+    }
+}
+
+/// Answers are validated with the chain of trust from the DS records of the root zone
+#[derive(Debug, Clone)]
+pub struct Dnssec {
+    pub trust_anchors: Vec<TrustAnchor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RebindProtectionConfig {
+    action: Option<RebindAction>,
+    whitelist: Option<Vec<String>>,
+}
+
+impl RebindProtectionConfig {
+    fn build(self, domains: &HashMap<String, Domains>) -> Result<RebindProtection, Error> {
+        let whitelist = self.whitelist.unwrap_or_default();
+        if let Some(d) = whitelist.iter().find(|d| !domains.contains_key(*d)) {
+            return Err(err_msg(format!(
+                "rebind-protection.whitelist: unknown domain list {}",
+                d
+            )));
+        }
+        Ok(RebindProtection {
+            action: self.action.unwrap_or(RebindAction::Strip),
+            whitelist,
+            ranges: IpRange::private(),
+        })
+    }
+}
+
+/// Guards against DNS rebinding. Answers of domains outside the whitelist
+/// must not resolve to the private, loopback or link-local `ranges`.
+#[derive(Debug)]
+pub struct RebindProtection {
+    pub action: RebindAction,
+    /// Names of domain lists
+    pub whitelist: Vec<String>,
+    pub ranges: IpRange,
+}
+
+#[derive(Debug, Deserialize)]
+struct BogusNxdomainConfig {
+    ranges: Vec<String>,
+    action: Option<BogusNxdomainAction>,
+}
+
+impl BogusNxdomainConfig {
+    fn build(self, ranges: &HashMap<String, IpRange>) -> Result<BogusNxdomain, Error> {
+        if self.ranges.is_empty() {
+            return Err(err_msg("bogus-nxdomain.ranges must not be empty"));
+        }
+        if let Some(r) = self.ranges.iter().find(|r| !ranges.contains_key(*r)) {
+            return Err(err_msg(format!(
+                "bogus-nxdomain.ranges: unknown range {}",
+                r
+            )));
+        }
+        Ok(BogusNxdomain {
+            ranges: self.ranges,
+            action: self.action.unwrap_or(BogusNxdomainAction::Nxdomain),
+        })
+    }
+}
+
+/// Responses with any address in `ranges` are answers made up by the upstream
+/// for domains which do not exist, like advertising pages of ISPs
+#[derive(Debug)]
+pub struct BogusNxdomain {
+    /// Names of IP ranges
+    pub ranges: Vec<String>,
+    pub action: BogusNxdomainAction,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum BogusNxdomainAction {
+    /// The response is turned into NXDOMAIN
+    #[serde(rename = "nxdomain")]
+    Nxdomain,
+    /// The response is dropped, so that other upstreams can answer
+    #[serde(rename = "drop")]
+    Drop,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum RebindAction {
+    /// The response is dropped
+    #[serde(rename = "drop")]
+    Drop,
+    /// The offending records are removed. The response is dropped if no address is left.
+    #[serde(rename = "strip")]
+    Strip,
+}
+
+#[derive(Debug, Deserialize)]
+struct DnstapConfig {
+    socket: Option<String>,
+    file: Option<String>,
+    identity: Option<String>,
+}
+
+impl DnstapConfig {
+    fn build(self) -> Result<DnstapOutput, Error> {
+        let target = match (self.socket, self.file) {
+            (Some(socket), None) => DnstapTarget::Socket(socket),
+            (None, Some(file)) => DnstapTarget::File(file),
+            _ => {
+                return Err(err_msg(
+                    "Exactly one of dnstap.socket and dnstap.file must be specified",
+                ));
+            }
+        };
+        Ok(DnstapOutput {
+            target,
+            identity: self.identity.unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DnstapOutput {
+    pub target: DnstapTarget,
+    pub identity: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum DnstapTarget {
+    /// A Frame Streams receiver listening on a Unix socket
+    Socket(String),
+    File(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminConfig {
+    listen: Option<SocketAddr>,
+    socket: Option<String>,
+}
+
+impl AdminConfig {
+    fn build(self) -> Result<AdminListener, Error> {
+        match (self.listen, self.socket) {
+            (Some(addr), None) => Ok(AdminListener::Tcp(addr)),
+            (None, Some(path)) => Ok(AdminListener::Unix(path)),
+            _ => Err(err_msg(
+                "Exactly one of admin.listen and admin.socket must be specified",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AdminListener {
+    Tcp(SocketAddr),
+    Unix(String),
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct LogConfig {
+    level: Option<String>,
+    modules: Option<HashMap<String, String>>,
+    format: Option<String>,
+    output: Option<String>,
+    path: Option<String>,
+    #[serde(rename = "max-size")]
+    max_size: Option<u64>,
+    keep: Option<usize>,
+}
+
+impl LogConfig {
+    fn build(self) -> Result<Log, Error> {
+        let mut filter = LogFilter::default();
+        if let Some(level) = self.level {
+            filter.level = parse_level(&level)?;
+        }
+        for (module, level) in self.modules.unwrap_or_default() {
+            filter.set_module(module, parse_level(&level)?);
+        }
+
+        let format = match self.format.as_ref().map(|f| f.as_str()) {
+            None | Some("compact") => LogFormat::Compact,
+            Some("full") => LogFormat::Full,
+            Some("json") => LogFormat::Json,
+            Some(format) => return Err(err_msg(format!("Invalid log format: {}", format))),
+        };
+
+        let output = match self.output.as_ref().map(|o| o.as_str()) {
+            None | Some("terminal") => LogOutput::Terminal,
+            Some("syslog") => LogOutput::Syslog,
+            Some("journald") => LogOutput::Journald,
+            Some("file") => LogOutput::File {
+                path: self.path.ok_or(err_msg("log.path is missing"))?,
+                // In MiB
+                max_size: self.max_size.unwrap_or(10) * 1024 * 1024,
+                keep: self.keep.unwrap_or(5),
+            },
+            Some(output) => return Err(err_msg(format!("Invalid log output: {}", output))),
+        };
+
+        Ok(Log {
+            filter,
+            format,
+            output,
         })
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct Log {
+    pub filter: LogFilter,
+    pub format: LogFormat,
+    pub output: LogOutput,
+}
+
+/// The minimum level of messages to be logged, which can be overridden per module.
+/// The longest matching module path takes effect.
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    pub level: Level,
+    pub modules: Vec<(String, Level)>,
+}
+
+impl LogFilter {
+    /// Applies directives in the `RUST_LOG` syntax, e.g. `info,yadd::dispatcher=debug`
+    pub fn apply(&mut self, directives: &str) -> Result<(), Error> {
+        for directive in directives
+            .split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+        {
+            let mut parts = directive.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(module), Some(level)) => {
+                    self.set_module(module.to_owned(), parse_level(level)?)
+                }
+                (Some(level), None) => self.level = parse_level(level)?,
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    fn set_module(&mut self, module: String, level: Level) {
+        self.modules.retain(|(m, _)| *m != module);
+        self.modules.push((module, level));
+    }
+
+    pub fn enabled(&self, level: Level, module: &str) -> bool {
+        let threshold = self
+            .modules
+            .iter()
+            .filter(|(m, _)| {
+                module == m
+                    || (module.starts_with(m.as_str()) && module[m.len()..].starts_with("::"))
+            })
+            .max_by_key(|(m, _)| m.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level);
+        level.is_at_least(threshold)
+    }
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter {
+            level: Level::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+fn parse_level(level: &str) -> Result<Level, Error> {
+    match level.to_lowercase().as_str() {
+        "trace" => Ok(Level::Trace),
+        "debug" => Ok(Level::Debug),
+        "info" => Ok(Level::Info),
+        "warn" | "warning" => Ok(Level::Warning),
+        "error" => Ok(Level::Error),
+        "crit" | "critical" => Ok(Level::Critical),
+        _ => Err(err_msg(format!("Invalid log level: {}", level))),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LogFormat {
+    Compact,
+    Full,
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Compact
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LogOutput {
+    /// Each logger writes to its own standard stream
+    Terminal,
+    /// The file is rotated when its size exceeds `max_size` bytes,
+    /// keeping at most `keep` old files
+    File {
+        path: String,
+        max_size: u64,
+        keep: usize,
+    },
+    Syslog,
+    Journald,
+}
+
+impl Default for LogOutput {
+    fn default() -> Self {
+        LogOutput::Terminal
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct UpstreamConfig {
     address: String,
@@ -115,6 +704,61 @@ struct UpstreamConfig {
     tls_host: Option<String>,
     #[serde(default = "UpstreamConfig::default_default")]
     default: bool,
+    timeout: Option<u64>,
+    #[serde(rename = "connect-timeout")]
+    connect_timeout: Option<u64>,
+    retries: Option<usize>,
+    #[serde(rename = "retry-interval")]
+    retry_interval: Option<u64>,
+    connections: Option<usize>,
+    #[serde(rename = "idle-timeout")]
+    idle_timeout: Option<u64>,
+    #[serde(rename = "edns-tcp-keepalive", default)]
+    keepalive: bool,
+    #[serde(default)]
+    reconnect: bool,
+    #[serde(rename = "max-queries")]
+    max_queries: Option<usize>,
+    #[serde(rename = "ca-file")]
+    ca_file: Option<String>,
+    #[serde(rename = "spki-pins", default)]
+    spki_pins: Vec<String>,
+    #[serde(rename = "client-cert")]
+    client_cert: Option<String>,
+    #[serde(rename = "client-key")]
+    client_key: Option<String>,
+    sni: Option<SniConfig>,
+    #[serde(rename = "insecure-skip-verify", default)]
+    insecure_skip_verify: bool,
+    bootstrap: Option<BootstrapConfig>,
+    #[serde(rename = "resolve-interval")]
+    resolve_interval: Option<u64>,
+    proxy: Option<String>,
+    #[serde(rename = "bind-address")]
+    bind_address: Option<IpAddr>,
+    interface: Option<String>,
+    fwmark: Option<u32>,
+    ecs: Option<String>,
+    #[serde(rename = "ecs-prefix-v4")]
+    ecs_prefix_v4: Option<u8>,
+    #[serde(rename = "ecs-prefix-v6")]
+    ecs_prefix_v6: Option<u8>,
+}
+
+/// Either the name of an upstream or static addresses
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum BootstrapConfig {
+    Upstream(String),
+    Static(Vec<IpAddr>),
+}
+
+/// Either a server name or a boolean
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SniConfig {
+    Enabled(bool),
+    Name(String),
 }
 
 impl UpstreamConfig {
@@ -123,22 +767,243 @@ impl UpstreamConfig {
     }
 
     fn build(self) -> Result<Upstream, Error> {
-        let mut address = self.address.parse::<SocketAddr>();
-        if let Err(_) = address {
-            address = self
-                .address
-                .parse::<IpAddr>()
-                .map(|addr| SocketAddr::new(addr, self.network.default_port()));
-        }
-        let address = address.map_err(|_| err_msg(format!("Invalid address: {}", self.address)))?;
-        match self.network {
-            NetworkType::Tcp => Ok(Upstream::TcpUpstream { address }),
-            NetworkType::Udp => Ok(Upstream::UdpUpstream { address }),
+        let kind = match self.network {
+            NetworkType::Tcp => UpstreamKind::TcpUpstream {
+                address: self.address()?,
+            },
+            NetworkType::Udp | NetworkType::Dnscrypt if self.proxy.is_some() => {
+                return Err(err_msg(
+                    "UDP and DNSCrypt upstreams can not be connected through a proxy",
+                ));
+            }
+            NetworkType::Udp => match self.address()? {
+                UpstreamAddress::Ip(address) => UpstreamKind::UdpUpstream { address },
+                UpstreamAddress::Name { .. } => {
+                    return Err(err_msg("UDP upstreams must be given by IP addresses"));
+                }
+            },
             NetworkType::Tls => {
-                let tls_host = self.tls_host.ok_or(err_msg("tls-host is missing"))?;
-                Ok(Upstream::TlsUpstream { address, tls_host })
+                let address = self.address()?;
+                // The hostname is the TLS host as well by default
+                let tls_host = match (self.tls_host, &address) {
+                    (Some(tls_host), _) => tls_host,
+                    (None, UpstreamAddress::Name { host, .. }) => host.to_string(),
+                    (None, UpstreamAddress::Ip(_)) => return Err(err_msg("tls-host is missing")),
+                };
+                let tls_host = tls_host.trim_end_matches('.').to_owned();
+                if self.client_cert.is_some() != self.client_key.is_some() {
+                    return Err(err_msg("client-cert and client-key must be set together"));
+                }
+                let tls = TlsOptions {
+                    ca_file: self.ca_file,
+                    spki_pins: self.spki_pins,
+                    client_cert: self.client_cert,
+                    client_key: self.client_key,
+                    sni: match self.sni {
+                        None | Some(SniConfig::Enabled(true)) => Sni::Host,
+                        Some(SniConfig::Enabled(false)) => Sni::Disabled,
+                        Some(SniConfig::Name(name)) => Sni::Name(name),
+                    },
+                    insecure_skip_verify: self.insecure_skip_verify,
+                };
+                UpstreamKind::TlsUpstream {
+                    address,
+                    tls_host,
+                    tls,
+                }
             }
+            NetworkType::Dnscrypt => UpstreamKind::DnscryptUpstream {
+                stamp: self.address.parse()?,
+            },
+        };
+
+        let timeout = Duration::from_millis(self.timeout.unwrap_or(5000));
+        if timeout == Duration::from_millis(0) {
+            return Err(err_msg("timeout must be positive"));
+        }
+        let options = UpstreamOptions {
+            timeout,
+            // The connection needs to be established before the query times out
+            connect_timeout: self
+                .connect_timeout
+                .map(Duration::from_millis)
+                .unwrap_or(timeout / 2),
+            retries: self.retries.unwrap_or(0),
+            retry_interval: Duration::from_millis(self.retry_interval.unwrap_or(0)),
+            proxy: Transpose::transpose(self.proxy.as_ref().map(|proxy| proxy.parse()))?,
+            socket: SocketOptions {
+                bind_address: self.bind_address,
+                interface: self.interface,
+                fwmark: self.fwmark,
+            },
+            ecs: ecs_policy(self.ecs, self.ecs_prefix_v4, self.ecs_prefix_v6)?,
+            pool: PoolOptions {
+                connections: self.connections.unwrap_or(1),
+                idle_timeout: self.idle_timeout.map(Duration::from_secs),
+                keepalive: self.keepalive,
+                reconnect: self.reconnect,
+                max_queries: self.max_queries,
+            },
+        };
+        if options.pool.connections == 0 {
+            return Err(err_msg("connections must be positive"));
         }
+        if cfg!(not(target_os = "linux"))
+            && (options.socket.interface.is_some() || options.socket.fwmark.is_some())
+        {
+            return Err(err_msg("interface and fwmark are only supported on Linux"));
+        }
+        Ok(Upstream { kind, options })
+    }
+
+    /// Accepts an IP address or a hostname, with an optional port
+    fn address(&self) -> Result<UpstreamAddress, Error> {
+        let default_port = self.network.default_port();
+        if let Ok(addr) = self.address.parse::<SocketAddr>() {
+            return Ok(UpstreamAddress::Ip(addr));
+        }
+        if let Ok(ip) = self.address.parse::<IpAddr>() {
+            return Ok(UpstreamAddress::Ip(SocketAddr::new(ip, default_port)));
+        }
+
+        let invalid = || err_msg(format!("Invalid address: {}", self.address));
+        let (host, port) = match self.address.rfind(':') {
+            Some(i) => (
+                &self.address[..i],
+                self.address[i + 1..].parse().map_err(|_| invalid())?,
+            ),
+            None => (self.address.as_str(), default_port),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let host = if host.ends_with('.') {
+            Name::from_str(host)
+        } else {
+            Name::from_str(&format!("{}.", host))
+        }
+        .map_err(|_| invalid())?;
+        let bootstrap = match &self.bootstrap {
+            Some(_) if self.proxy.is_some() => {
+                return Err(err_msg(
+                    "bootstrap is not needed because the proxy resolves the hostname",
+                ));
+            }
+            None if self.proxy.is_some() => Bootstrap::Proxy,
+            Some(BootstrapConfig::Upstream(name)) => {
+                let interval = Duration::from_secs(self.resolve_interval.unwrap_or(300));
+                if interval == Duration::from_secs(0) {
+                    return Err(err_msg("resolve-interval must be positive"));
+                }
+                Bootstrap::Upstream {
+                    name: name.clone(),
+                    interval,
+                }
+            }
+            Some(BootstrapConfig::Static(ips)) if !ips.is_empty() => Bootstrap::Static(ips.clone()),
+            _ => {
+                return Err(err_msg(format!(
+                    "{} is not an IP address. A bootstrap upstream or addresses are required.",
+                    self.address
+                )));
+            }
+        };
+        Ok(UpstreamAddress::Name {
+            host,
+            port,
+            bootstrap,
+        })
+    }
+}
+
+/// `timeout` applies to each attempt. A failed query is retried `retries` times,
+/// waiting `retry_interval` before every retry.
+#[derive(Debug, Clone)]
+pub struct UpstreamOptions {
+    pub timeout: Duration,
+    /// Only used by TCP and TLS upstreams
+    pub connect_timeout: Duration,
+    pub retries: usize,
+    pub retry_interval: Duration,
+    /// Only used by TCP and TLS upstreams
+    pub pool: PoolOptions,
+    /// Only used by TCP and TLS upstreams
+    pub proxy: Option<Proxy>,
+    pub socket: SocketOptions,
+    /// Overridden by the dispatching rule
+    pub ecs: Option<EcsPolicy>,
+}
+
+/// Parses `strip`, `passthrough`, `client` or a fixed subnet.
+/// The prefix lengths only apply to `client`.
+fn ecs_policy(
+    ecs: Option<String>,
+    prefix_v4: Option<u8>,
+    prefix_v6: Option<u8>,
+) -> Result<Option<EcsPolicy>, Error> {
+    let policy = match ecs.as_ref().map(String::as_str) {
+        None => None,
+        Some("strip") => Some(EcsPolicy::Strip),
+        Some("passthrough") => Some(EcsPolicy::Passthrough),
+        Some("client") => {
+            let v4_prefix = prefix_v4.unwrap_or(24);
+            let v6_prefix = prefix_v6.unwrap_or(56);
+            if v4_prefix > 32 || v6_prefix > 128 {
+                return Err(err_msg("ecs-prefix-v4 or ecs-prefix-v6 is too long"));
+            }
+            return Ok(Some(EcsPolicy::Client {
+                v4_prefix,
+                v6_prefix,
+            }));
+        }
+        Some(subnet) => {
+            let subnet: IpNet = subnet
+                .parse()
+                .map_err(|_| err_msg(format!("Invalid ecs: {}", subnet)))?;
+            Some(EcsPolicy::Subnet(ClientSubnet::new(
+                subnet.addr(),
+                subnet.prefix_len(),
+            )))
+        }
+    };
+    if prefix_v4.is_some() || prefix_v6.is_some() {
+        return Err(err_msg(
+            "ecs-prefix-v4 and ecs-prefix-v6 require ecs = \"client\"",
+        ));
+    }
+    Ok(policy)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Group {
+    pub upstreams: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+}
+
+/// Decides which upstreams in a group a query is forwarded to
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub enum Strategy {
+    /// All upstreams at the same time
+    #[serde(rename = "parallel")]
+    Parallel,
+    /// One upstream, in turn
+    #[serde(rename = "round-robin")]
+    RoundRobin,
+    /// One upstream picked randomly
+    #[serde(rename = "random")]
+    Random,
+    /// One upstream. The next one is tried only if the previous one fails.
+    #[serde(rename = "failover")]
+    Failover,
+    /// The upstream with the lowest estimated latency
+    #[serde(rename = "fastest")]
+    Fastest,
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Strategy::Parallel
     }
 }
 
@@ -150,6 +1015,9 @@ enum NetworkType {
     Udp,
     #[serde(rename = "tls")]
     Tls,
+    /// The address is an sdns:// stamp
+    #[serde(rename = "dnscrypt")]
+    Dnscrypt,
 }
 
 impl NetworkType {
@@ -157,6 +1025,7 @@ impl NetworkType {
         match self {
             NetworkType::Tcp | NetworkType::Udp => 53,
             NetworkType::Tls => 853,
+            NetworkType::Dnscrypt => 443,
         }
     }
 }
@@ -250,20 +1119,32 @@ struct RequestRuleConfig {
     domains: Option<Vec<String>>,
     types: Option<Vec<String>>,
     upstreams: Vec<String>,
+    prefer: Option<Vec<String>>,
+    #[serde(rename = "grace-period")]
+    grace_period: Option<u64>,
+    ecs: Option<String>,
+    #[serde(rename = "ecs-prefix-v4")]
+    ecs_prefix_v4: Option<u8>,
+    #[serde(rename = "ecs-prefix-v6")]
+    ecs_prefix_v6: Option<u8>,
 }
 
 impl RequestRuleConfig {
-    fn build(self) -> Result<RequestRule, Error> {
+    fn build(self, groups: &HashMap<String, Group>) -> Result<RequestRule, Error> {
         let types = Transpose::transpose(self.types.map(|v| {
             v.iter()
                 .map(|t| RecordType::from_str(t))
                 .collect::<Result<Vec<_>, _>>()
         }))?;
 
+        let priority = Priority::build(self.prefer, self.grace_period, &self.upstreams, groups)?;
+
         Ok(RequestRule {
             domains: self.domains,
             types,
             upstreams: self.upstreams,
+            priority,
+            ecs: ecs_policy(self.ecs, self.ecs_prefix_v4, self.ecs_prefix_v6)?,
         })
     }
 }
@@ -273,20 +1154,171 @@ pub struct RequestRule {
     pub domains: Option<Vec<String>>,
     pub types: Option<Vec<RecordType>>,
     pub upstreams: Vec<String>,
+    pub priority: Option<Priority>,
+    /// Applies to all the upstreams of the rule
+    pub ecs: Option<EcsPolicy>,
+}
+
+/// Responses from the preferred upstreams are waited for until the grace period ends,
+/// even if other upstreams have answered earlier.
+#[derive(Debug, Clone)]
+pub struct Priority {
+    pub upstreams: Vec<String>,
+    pub grace_period: Duration,
+}
+
+impl Priority {
+    const DEFAULT_GRACE_PERIOD: u64 = 100;
+
+    /// The preferred upstreams must be among `candidates`, the upstreams and groups
+    /// queries are dispatched to, or members of the groups.
+    fn build(
+        prefer: Option<Vec<String>>,
+        grace_period: Option<u64>,
+        candidates: &[String],
+        groups: &HashMap<String, Group>,
+    ) -> Result<Option<Self>, Error> {
+        match (prefer, grace_period) {
+            (Some(upstreams), grace_period) => {
+                let reachable = |name: &String| {
+                    candidates.iter().any(|c| {
+                        c == name
+                            || groups
+                                .get(c)
+                                .map(|g| g.upstreams.contains(name))
+                                .unwrap_or(false)
+                    })
+                };
+                if let Some(name) = upstreams.iter().find(|name| !reachable(name)) {
+                    return Err(err_msg(format!(
+                        "Preferred upstream {} is not among the upstreams to query",
+                        name
+                    )));
+                }
+                Ok(Some(Priority {
+                    upstreams,
+                    grace_period: Duration::from_millis(
+                        grace_period.unwrap_or(Self::DEFAULT_GRACE_PERIOD),
+                    ),
+                }))
+            }
+            (None, Some(_)) => Err(err_msg("grace-period is set without prefer")),
+            (None, None) => Ok(None),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
+struct ResponseRuleConfig {
+    upstreams: Option<Vec<String>>,
+    ranges: Option<Vec<String>>,
+    domains: Option<Vec<String>>,
+    action: ActionType,
+    #[serde(rename = "match")]
+    match_mode: Option<MatchMode>,
+    ttl: Option<u32>,
+    #[serde(rename = "ttl-min")]
+    ttl_min: Option<u32>,
+    #[serde(rename = "ttl-max")]
+    ttl_max: Option<u32>,
+    address: Option<IpAddr>,
+    dnssec: Option<Status>,
+}
+
+impl ResponseRuleConfig {
+    fn build(self) -> Result<ResponseRule, Error> {
+        let action = match self.action {
+            ActionType::Accept => RuleAction::Accept,
+            ActionType::Drop => RuleAction::Drop,
+            ActionType::RewriteTtl => {
+                let (min, max) = match (self.ttl, self.ttl_min, self.ttl_max) {
+                    (Some(ttl), None, None) => (ttl, ttl),
+                    (None, None, None) => {
+                        return Err(err_msg("rewrite-ttl requires ttl, ttl-min or ttl-max"));
+                    }
+                    (None, min, max) => (min.unwrap_or(0), max.unwrap_or(u32::max_value())),
+                    _ => return Err(err_msg("ttl cannot be used with ttl-min or ttl-max")),
+                };
+                if min > max {
+                    return Err(err_msg("ttl-min must not be greater than ttl-max"));
+                }
+                RuleAction::RewriteTtl { min, max }
+            }
+            ActionType::ReplaceIp => {
+                let address = self
+                    .address
+                    .ok_or(err_msg("replace-ip requires an address"))?;
+                RuleAction::ReplaceIp(address)
+            }
+            ActionType::StripAaaa => RuleAction::StripAaaa,
+            ActionType::FilterRecords => RuleAction::FilterRecords,
+        };
+
+        Ok(ResponseRule {
+            upstreams: self.upstreams,
+            ranges: self.ranges,
+            domains: self.domains,
+            match_mode: self.match_mode.unwrap_or(MatchMode::First),
+            dnssec: self.dnssec,
+            action,
+        })
+    }
+}
+
+#[derive(Debug)]
 pub struct ResponseRule {
     pub upstreams: Option<Vec<String>>,
     pub ranges: Option<Vec<String>>,
     pub domains: Option<Vec<String>>,
+    pub match_mode: MatchMode,
+    /// Matches only responses validated with this status
+    pub dnssec: Option<Status>,
     pub action: RuleAction,
 }
 
+/// Decides which addresses in the answers are checked against the ranges
 #[derive(Debug, Deserialize, Clone, Copy)]
-pub enum RuleAction {
+pub enum MatchMode {
+    /// Only the first address is checked
+    #[serde(rename = "first")]
+    First,
+    /// The rule matches if any address is inside the ranges
+    #[serde(rename = "any")]
+    Any,
+    /// The rule matches if all addresses are inside the ranges
+    #[serde(rename = "all")]
+    All,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+enum ActionType {
     #[serde(rename = "accept")]
     Accept,
     #[serde(rename = "drop")]
     Drop,
+    #[serde(rename = "rewrite-ttl")]
+    RewriteTtl,
+    #[serde(rename = "replace-ip")]
+    ReplaceIp,
+    #[serde(rename = "strip-aaaa")]
+    StripAaaa,
+    #[serde(rename = "filter-records")]
+    FilterRecords,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RuleAction {
+    Accept,
+    Drop,
+    /// Clamp the TTL of every answer record into `[min, max]`
+    RewriteTtl {
+        min: u32,
+        max: u32,
+    },
+    /// Replace the addresses inside the rule's ranges with a fixed one
+    ReplaceIp(IpAddr),
+    /// Remove all AAAA records from the answers
+    StripAaaa,
+    /// Remove the A/AAAA records inside the rule's ranges
+    FilterRecords,
 }
@@ -1,81 +1,341 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::config::Domains;
-use crate::config::Upstream;
-use crate::config::{Config, RequestRule, ResponseRule, RuleAction};
-use crate::ip::IpRange;
-use crate::resolver::tcp::{
-    SimpleTcpDnsStreamBuilder, SimpleTcpResolver, TlsDnsStreamBuilder, TlsResolver,
+use crate::config::{BogusNxdomain, BogusNxdomainAction};
+use crate::config::{Bootstrap, UpstreamAddress, UpstreamKind, UpstreamOptions};
+use crate::config::{
+    Config, Group, MatchMode, Priority, RebindAction, RebindProtection, RequestRule, ResponseRule,
+    RuleAction, Strategy,
 };
+use crate::dnstap::{self, Dnstap, DnstapMessage, DnstapResolver, MessageKind, SocketProtocol};
+use crate::health::{self, Health};
+use crate::ip::IpRange;
+use crate::metrics;
+use crate::querylog::{self, OutcomeResult, QueryLog};
+use crate::resolver::bootstrap;
+use crate::resolver::connect::{Connector, Endpoint, ResolvedAddrs};
+use crate::resolver::dnscrypt::DnscryptResolver;
+use crate::resolver::dnssec::{Status, Validator};
+use crate::resolver::ecs::{ClientSubnet, EcsPolicy, EDNS_CLIENT_SUBNET};
+use crate::resolver::measured::MeasuredResolver;
+use crate::resolver::retry::RetryResolver;
+use crate::resolver::tcp::{SimpleTcpDnsStreamBuilder, SimpleTcpResolver};
+use crate::resolver::tls::{TlsDnsStreamBuilder, TlsResolver};
 use crate::resolver::udp::SimpleUdpResolver;
-use crate::resolver::Resolver;
+use crate::resolver::{QueryOptions, Resolver};
 use crate::{Transpose, STDERR};
 
+use failure::{err_msg, Error};
+use parking_lot::{Mutex, RwLock};
+use rand::Rng;
+use serde_derive::Serialize;
 use slog::{debug, error};
 use tokio::prelude::*;
+use tokio::timer::{Delay, Timeout};
 use trust_dns::op::{DnsResponse, Query};
 use trust_dns::serialize::binary::{BinDecoder, BinEncodable};
-use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::error::{ProtoError, ProtoErrorKind};
 use trust_dns_proto::op::header::Header;
 use trust_dns_proto::op::header::MessageType;
 use trust_dns_proto::op::response_code::ResponseCode;
+use trust_dns_proto::rr::dns_class::DNSClass;
+use trust_dns_proto::rr::rdata::opt::EdnsCode;
 use trust_dns_proto::rr::record_data::RData;
-use trust_dns_proto::rr::RrsetRecords;
+use trust_dns_proto::rr::record_type::RecordType;
+use trust_dns_proto::rr::{Name, Record, RrsetRecords};
 use trust_dns_server::authority::{AuthLookup, LookupRecords, MessageResponseBuilder, Queries};
 use trust_dns_server::server::{Request, RequestHandler, ResponseHandler};
 
 #[derive(Clone)]
 pub struct Dispatcher {
     defaults: Arc<Vec<String>>,
-    resolvers: Arc<HashMap<String, Arc<Resolver>>>,
+    default_priority: Arc<Option<Priority>>,
+    deadline: Option<Duration>,
+    resolvers: Arc<HashMap<String, MeasuredResolver>>,
+    ecs: Arc<HashMap<String, EcsPolicy>>,
+    groups: Arc<HashMap<String, UpstreamGroup>>,
     domains: Arc<HashMap<String, Domains>>,
     ranges: Arc<HashMap<String, IpRange>>,
     request_rules: Arc<Vec<RequestRule>>,
     response_rules: Arc<Vec<ResponseRule>>,
+    rebind_protection: Arc<Option<RebindProtection>>,
+    bogus_nxdomain: Arc<Option<BogusNxdomain>>,
+    query_log: Option<Arc<QueryLog>>,
+    dnstap: Option<Arc<Dnstap>>,
+    validator: Option<Validator>,
+    bind: SocketAddr,
+    switches: Arc<Switches>,
+    in_flight: Arc<Mutex<HashMap<InFlightKey, SharedResponse>>>,
+    // Set when the dispatcher is replaced after reloading the config
+    retired: Arc<AtomicBool>,
 }
 
 impl Dispatcher {
-    pub fn new(config: Config) -> Self {
+    pub fn new(
+        config: Config,
+        query_log: Option<Arc<QueryLog>>,
+        dnstap: Option<Arc<Dnstap>>,
+    ) -> Result<Self, Error> {
+        let retired = Arc::new(AtomicBool::new(false));
+        let validator =
+            Transpose::transpose(config.dnssec.map(|d| Validator::new(d.trust_anchors)))?;
+        let mut resolutions = Vec::new();
+        let ecs: HashMap<_, _> = config
+            .upstreams
+            .iter()
+            .filter_map(|(name, upstream)| Some((name.clone(), upstream.options.ecs.clone()?)))
+            .collect();
+
         let resolvers: HashMap<_, _> = config
             .upstreams
             .iter()
             .map(|(name, upstream)| {
-                (
-                    name.to_owned(),
-                    match upstream {
-                        Upstream::TcpUpstream { address } => Arc::new(SimpleTcpResolver::new(
-                            SimpleTcpDnsStreamBuilder::new(*address),
+                let options = &upstream.options;
+                let resolver = match &upstream.kind {
+                    UpstreamKind::TcpUpstream { address } => {
+                        Arc::new(SimpleTcpResolver::with_options(
+                            SimpleTcpDnsStreamBuilder::with_connector(connector(
+                                address,
+                                options,
+                                &mut resolutions,
+                            )),
+                            options.timeout,
+                            options.connect_timeout,
+                            options.pool.clone(),
+                        )) as Arc<Resolver>
+                    }
+                    UpstreamKind::UdpUpstream { address } => Arc::new(
+                        SimpleUdpResolver::with_options(
+                            *address,
+                            options.timeout,
+                            options.socket.clone(),
+                        )
+                        .map_err(|e| err_msg(format!("Upstream {}: {}", name, e)))?,
+                    ),
+                    UpstreamKind::TlsUpstream {
+                        address,
+                        tls_host,
+                        tls,
+                    } => {
+                        let builder = TlsDnsStreamBuilder::with_options(
+                            connector(address, options, &mut resolutions),
+                            tls_host.clone(),
+                            tls,
+                        )
+                        .map_err(|e| err_msg(format!("Upstream {}: {}", name, e)))?;
+                        Arc::new(TlsResolver::with_options(
+                            builder,
+                            options.timeout,
+                            options.connect_timeout,
+                            options.pool.clone(),
                         ))
-                            as Arc<Resolver>,
-                        Upstream::UdpUpstream { address } => {
-                            Arc::new(SimpleUdpResolver::new(*address))
-                        }
-                        Upstream::TlsUpstream { address, tls_host } => Arc::new(TlsResolver::new(
-                            TlsDnsStreamBuilder::new(*address, tls_host.clone()),
-                        )),
-                    },
-                )
+                    }
+                    UpstreamKind::DnscryptUpstream { stamp } => Arc::new(
+                        DnscryptResolver::with_options(
+                            stamp.clone(),
+                            options.timeout,
+                            options.socket.clone(),
+                        )
+                        .map_err(|e| err_msg(format!("Upstream {}: {}", name, e)))?,
+                    ),
+                };
+                Ok((name.to_owned(), resolver, upstream))
+            })
+            .collect::<Result<Vec<_>, Error>>()?
+            .into_iter()
+            .map(|(name, resolver, upstream)| {
+                let resolver = match &dnstap {
+                    Some(dnstap) => {
+                        let (address, protocol) = match &upstream.kind {
+                            UpstreamKind::TcpUpstream { address } => {
+                                (address.ip(), SocketProtocol::Tcp)
+                            }
+                            UpstreamKind::UdpUpstream { address } => {
+                                (Some(*address), SocketProtocol::Udp)
+                            }
+                            UpstreamKind::TlsUpstream { address, .. } => {
+                                (address.ip(), SocketProtocol::Dot)
+                            }
+                            UpstreamKind::DnscryptUpstream { stamp } => {
+                                (Some(stamp.addr), SocketProtocol::DnscryptUdp)
+                            }
+                        };
+                        Arc::new(DnstapResolver::new(
+                            resolver,
+                            dnstap.clone(),
+                            address,
+                            protocol,
+                        )) as Arc<Resolver>
+                    }
+                    None => resolver,
+                };
+                let options = &upstream.options;
+                let resolver = if options.retries > 0 {
+                    Arc::new(RetryResolver::new(
+                        resolver,
+                        options.retries,
+                        options.retry_interval,
+                    )) as Arc<Resolver>
+                } else {
+                    resolver
+                };
+                (name, resolver)
+            })
+            .map(|(name, resolver)| {
+                let resolver = MeasuredResolver::new(resolver);
+                match &config.health_check {
+                    Some(health_check) => {
+                        let health = Health::new(name.clone(), health_check.failures);
+                        let resolver = resolver.with_health(health);
+                        health::spawn_probes(
+                            name.clone(),
+                            resolver.clone(),
+                            health_check,
+                            retired.clone(),
+                        );
+                        (name, resolver)
+                    }
+                    None => (name, resolver),
+                }
             })
             .collect();
 
-        Dispatcher {
+        for (host, upstream, addrs, interval) in resolutions {
+            bootstrap::spawn_resolution(
+                host,
+                resolvers[upstream].clone(),
+                addrs,
+                interval,
+                retired.clone(),
+            );
+        }
+
+        let groups: HashMap<_, _> = config
+            .groups
+            .into_iter()
+            .map(|(name, group)| (name, UpstreamGroup::new(group)))
+            .collect();
+
+        Ok(Dispatcher {
             defaults: Arc::new(config.default_upstreams),
+            default_priority: Arc::new(config.default_priority),
+            deadline: config.deadline,
             resolvers: Arc::new(resolvers),
+            ecs: Arc::new(ecs),
+            groups: Arc::new(groups),
             domains: Arc::new(config.domains),
             ranges: Arc::new(config.ranges),
             request_rules: Arc::new(config.request_rules),
             response_rules: Arc::new(config.response_rules),
+            rebind_protection: Arc::new(config.rebind_protection),
+            bogus_nxdomain: Arc::new(config.bogus_nxdomain),
+            query_log,
+            dnstap,
+            validator,
+            bind: config.bind,
+            switches: Arc::new(Switches::default()),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            retired,
+        })
+    }
+
+    /// Stops the background tasks of the dispatcher
+    fn retire(&self) {
+        self.retired.store(true, Ordering::Relaxed);
+    }
+
+    pub fn upstreams(&self) -> Vec<UpstreamStatus> {
+        let disabled = self.switches.upstreams.read();
+        let mut upstreams: Vec<_> = self
+            .resolvers
+            .iter()
+            .map(|(name, resolver)| UpstreamStatus {
+                name: name.clone(),
+                enabled: !disabled.contains(name),
+                healthy: resolver.is_healthy(),
+                connection: resolver.connection_state(),
+                latency_ms: resolver.latency().map(querylog::as_millis),
+            })
+            .collect();
+        upstreams.sort_by(|a, b| a.name.cmp(&b.name));
+        upstreams
+    }
+
+    pub fn set_upstream_enabled(&self, name: &str, enabled: bool) -> Result<(), Error> {
+        if !self.resolvers.contains_key(name) {
+            return Err(err_msg(format!("Unknown upstream: {}", name)));
         }
+        let mut disabled = self.switches.upstreams.write();
+        if enabled {
+            disabled.remove(name);
+        } else {
+            disabled.insert(name.to_owned());
+        }
+        Ok(())
     }
 
-    fn check_response(&self, domain: &str, upstream_name: &str, resp: &DnsResponse) -> RuleAction {
-        let answers = resp.answers();
+    pub fn set_request_rule_enabled(&self, index: usize, enabled: bool) -> Result<(), Error> {
+        if index >= self.request_rules.len() {
+            return Err(err_msg(format!("Unknown request rule: {}", index)));
+        }
+        Self::switch(&self.switches.request_rules, index, enabled);
+        Ok(())
+    }
+
+    pub fn set_response_rule_enabled(&self, index: usize, enabled: bool) -> Result<(), Error> {
+        if index >= self.response_rules.len() {
+            return Err(err_msg(format!("Unknown response rule: {}", index)));
+        }
+        Self::switch(&self.switches.response_rules, index, enabled);
+        Ok(())
+    }
+
+    fn switch(disabled: &RwLock<HashSet<usize>>, index: usize, enabled: bool) {
+        if enabled {
+            disabled.write().remove(&index);
+        } else {
+            disabled.write().insert(index);
+        }
+    }
 
+    /// Applies the response rules to `resp`, modifying it in place if needed.
+    /// `dnssec` is the result of validating `resp`, if enabled.
+    /// Returns either `RuleAction::Accept` or `RuleAction::Drop`,
+    /// with what makes the decision.
+    fn check_response(
+        &self,
+        domain: &str,
+        upstream_name: &str,
+        resp: &mut DnsResponse,
+        dnssec: Option<Status>,
+    ) -> (RuleAction, Decider) {
         // Drop empty response
-        if answers.is_empty() {
-            return RuleAction::Drop;
+        if resp.answers().is_empty() {
+            metrics::RESPONSE_RULE_DROPS
+                .with_label_values(&["empty", upstream_name])
+                .inc();
+            return (RuleAction::Drop, Decider::Check("empty"));
+        }
+
+        if let Some(bogus) = &*self.bogus_nxdomain {
+            if let Some(action) = check_bogus_nxdomain(&self.ranges, bogus, upstream_name, resp) {
+                return (action, Decider::Check("bogus-nxdomain"));
+            }
+        }
+
+        if let Some(protection) = &*self.rebind_protection {
+            if !check_rebinding(&self.domains, protection, domain, resp) {
+                metrics::RESPONSE_RULE_DROPS
+                    .with_label_values(&["rebind", upstream_name])
+                    .inc();
+                return (RuleAction::Drop, Decider::Check("rebind"));
+            }
         }
 
         let check_upstream = |rule: &ResponseRule| {
@@ -85,83 +345,215 @@ impl Dispatcher {
                 .unwrap_or(true)
         };
 
-        let check_ranges = |rule: &ResponseRule| {
-            rule.ranges
-                .as_ref()
-                .map(|r| {
-                    r.iter().any(|range_pattern| {
-                        // Process the leading `!`
-                        let range_name = range_pattern.trim_start_matches('!');
-                        let toggle = (range_pattern.len() - range_name.len()) % 2 == 1;
-
-                        // See if the range contains the IP
-                        let range = self.ranges.get(range_name);
-                        range
-                            .map(|range| {
-                                answers
-                                    .iter()
-                                    .filter_map(|rec| match rec.rdata() {
-                                        RData::A(ip) => Some(range.contains((*ip).into())),
-                                        RData::AAAA(ip) => Some(range.contains((*ip).into())),
-                                        _ => None,
-                                    })
-                                    .next()
-                                    .unwrap_or(false)
-                                    ^ toggle // toggle result according to the number of !
-                            })
-                            .unwrap_or(false)
-                    })
-                })
-                .unwrap_or(true) // No ranges field means matching all ranges
+        let check_dnssec =
+            |rule: &ResponseRule| rule.dnssec.map(|s| Some(s) == dnssec).unwrap_or(true);
+
+        let check_ranges = |rule: &ResponseRule, answers: &[Record]| {
+            let mut ips = answers
+                .iter()
+                .filter_map(|rec| rec.rdata().to_ip_addr())
+                .peekable();
+            if ips.peek().is_none() {
+                return self.ip_in_ranges(rule, None);
+            }
+            match rule.match_mode {
+                MatchMode::First => self.ip_in_ranges(rule, ips.next()),
+                MatchMode::Any => ips.any(|ip| self.ip_in_ranges(rule, Some(ip))),
+                MatchMode::All => ips.all(|ip| self.ip_in_ranges(rule, Some(ip))),
+            }
         };
 
-        let check_domains = |rule: &ResponseRule| {
-            rule.domains
-                .as_ref()
-                .map(|d| {
-                    d.iter().any(|domains_pattern| {
-                        // Process the leading `!`
-                        let domains_tag = domains_pattern.trim_start_matches('!');
-                        let toggle = (domains_pattern.len() - domains_pattern.len()) % 2 == 1;
-
-                        let domains = self.domains.get(domains_tag);
-                        domains
-                            .map(|domains| domains.regex_set.is_match(domain) ^ toggle)
-                            .unwrap_or(false)
-                    })
-                })
-                .unwrap_or(true) // No domains field means matching all domains
+        // The queried domain and all CNAME targets in the answers are checked
+        // against the domain lists.
+        let mut names = vec![domain.to_owned()];
+        names.extend(resp.answers().iter().filter_map(|rec| match rec.rdata() {
+            RData::CNAME(name) => Some(name.to_ascii()),
+            _ => None,
+        }));
+        let check_domains =
+            |rule: &ResponseRule| domains_match(&self.domains, &rule.domains, &names);
+
+        let drop = |index: usize| {
+            metrics::RESPONSE_RULE_DROPS
+                .with_label_values(&[&index.to_string(), upstream_name])
+                .inc();
+            (RuleAction::Drop, Decider::Rule(index))
         };
 
-        self.response_rules
+        let disabled = self.switches.response_rules.read();
+        for (index, rule) in self
+            .response_rules
             .iter()
-            .find(|rule| check_upstream(rule) && check_ranges(rule) && check_domains(rule))
-            .map(|rule| rule.action)
-            .unwrap_or(RuleAction::Accept)
+            .enumerate()
+            .filter(|(index, _)| !disabled.contains(index))
+            .filter(|(_, rule)| check_upstream(rule) && check_dnssec(rule) && check_domains(rule))
+        {
+            match rule.action {
+                RuleAction::Accept => {
+                    if check_ranges(rule, resp.answers()) {
+                        return (RuleAction::Accept, Decider::Rule(index));
+                    }
+                }
+                RuleAction::Drop => {
+                    if check_ranges(rule, resp.answers()) {
+                        return drop(index);
+                    }
+                }
+                RuleAction::RewriteTtl { min, max } => {
+                    if check_ranges(rule, resp.answers()) {
+                        let mut answers = resp.take_answers();
+                        for rec in &mut answers {
+                            let ttl = rec.ttl().max(min).min(max);
+                            rec.set_ttl(ttl);
+                        }
+                        resp.insert_answers(answers);
+                    }
+                }
+                RuleAction::StripAaaa => {
+                    if check_ranges(rule, resp.answers()) {
+                        let mut answers = resp.take_answers();
+                        answers.retain(|rec| rec.rr_type() != RecordType::AAAA);
+                        resp.insert_answers(answers);
+                    }
+                }
+                RuleAction::ReplaceIp(addr) => {
+                    let mut answers = resp.take_answers();
+                    for rec in &mut answers {
+                        let rdata = match (rec.rdata(), addr) {
+                            (RData::A(ip), IpAddr::V4(addr))
+                                if self.ip_in_ranges(rule, Some((*ip).into())) =>
+                            {
+                                RData::A(addr)
+                            }
+                            (RData::AAAA(ip), IpAddr::V6(addr))
+                                if self.ip_in_ranges(rule, Some((*ip).into())) =>
+                            {
+                                RData::AAAA(addr)
+                            }
+                            _ => continue,
+                        };
+                        rec.set_rdata(rdata);
+                    }
+                    resp.insert_answers(answers);
+                }
+                RuleAction::FilterRecords => {
+                    let has_address = |answers: &[Record]| {
+                        answers.iter().any(|rec| rec.rdata().to_ip_addr().is_some())
+                    };
+                    let mut answers = resp.take_answers();
+                    let had_address = has_address(&answers);
+                    answers.retain(|rec| match rec.rdata().to_ip_addr() {
+                        Some(ip) => !self.ip_in_ranges(rule, Some(ip)),
+                        None => true,
+                    });
+                    // Every address is filtered out. Give other upstreams a chance.
+                    if had_address && !has_address(&answers) {
+                        return drop(index);
+                    }
+                    resp.insert_answers(answers);
+                }
+            }
+        }
+
+        (RuleAction::Accept, Decider::Default)
     }
 
-    fn dispatch(&self, query: &Query) -> Vec<(&str, Arc<Resolver>)> {
-        let name = query.name().to_ascii();
-        // TODO: Most part of this is identical to that in the `check_response` function
-        //       Need refactoring
-        let check_domains = |rule: &RequestRule| {
-            rule.domains
-                .as_ref()
-                .map(|d| {
-                    d.iter().any(|domains_pattern| {
-                        // Process the leading `!`
-                        let domains_tag = domains_pattern.trim_start_matches('!');
-                        let toggle = (domains_pattern.len() - domains_pattern.len()) % 2 == 1;
-
-                        let domains = self.domains.get(domains_tag);
-                        domains
-                            .map(|domains| domains.regex_set.is_match(&name) ^ toggle)
-                            .unwrap_or(false)
-                    })
+    /// Tells if `ip` is inside the ranges of the rule.
+    /// `None` stands for a response or record without any address.
+    fn ip_in_ranges(&self, rule: &ResponseRule, ip: Option<IpAddr>) -> bool {
+        rule.ranges
+            .as_ref()
+            .map(|r| {
+                r.iter().any(|range_pattern| {
+                    // Process the leading `!`
+                    let range_name = range_pattern.trim_start_matches('!');
+                    let toggle = (range_pattern.len() - range_name.len()) % 2 == 1;
+
+                    // See if the range contains the IP
+                    let range = self.ranges.get(range_name);
+                    range
+                        .map(|range| {
+                            let contains = ip.map(|ip| range.contains(ip)).unwrap_or(false);
+                            contains ^ toggle // toggle result according to the number of !
+                        })
+                        .unwrap_or(false)
                 })
-                .unwrap_or(true) // No domains field means matching all domains
+            })
+            .unwrap_or(true) // No ranges field means matching all ranges
+    }
+
+    /// Resolves the names of upstreams and groups into the upstreams to query.
+    /// Each of the returned chains is queried in parallel. The upstreams in a chain
+    /// are tried one after another until one of them succeeds.
+    /// Unhealthy upstreams are skipped, unless all of them are unhealthy.
+    /// Disabled upstreams are always skipped.
+    fn select_upstreams<'a>(&'a self, names: &'a [String]) -> Vec<Chain<'a>> {
+        let chains = self.select_upstreams_with(names, true);
+        if chains.is_empty() {
+            self.select_upstreams_with(names, false)
+        } else {
+            chains
+        }
+    }
+
+    fn select_upstreams_with<'a>(
+        &'a self,
+        names: &'a [String],
+        healthy_only: bool,
+    ) -> Vec<Chain<'a>> {
+        let disabled = self.switches.upstreams.read();
+        let resolver = |u: &'a String| {
+            self.resolvers
+                .get(u)
+                .filter(|_| !disabled.contains(u))
+                .filter(|r| !healthy_only || r.is_healthy())
+                .map(|r| (u.as_str(), r.clone()))
         };
 
+        let mut chains = Vec::new();
+        for name in names {
+            let group = match self.groups.get(name) {
+                Some(group) => group,
+                None => {
+                    chains.extend(resolver(name).map(|r| vec![r]));
+                    continue;
+                }
+            };
+            let mut members: Vec<_> = group.upstreams.iter().filter_map(&resolver).collect();
+            if members.is_empty() {
+                continue;
+            }
+            match group.strategy {
+                Strategy::Parallel => chains.extend(members.into_iter().map(|r| vec![r])),
+                Strategy::Failover => chains.push(members),
+                Strategy::RoundRobin => {
+                    let i = group.next.fetch_add(1, Ordering::Relaxed) % members.len();
+                    chains.push(vec![members.swap_remove(i)]);
+                }
+                Strategy::Random => {
+                    let i = rand::thread_rng().gen_range(0, members.len());
+                    chains.push(vec![members.swap_remove(i)]);
+                }
+                Strategy::Fastest => {
+                    // Upstreams never measured are tried first
+                    let fastest = members
+                        .into_iter()
+                        .min_by_key(|(_, r)| r.latency().unwrap_or_default());
+                    chains.extend(fastest.map(|r| vec![r]));
+                }
+            }
+        }
+        chains
+    }
+
+    /// Returns the upstreams to query, the priority to apply
+    /// and the index of the matched dispatching rule
+    fn dispatch(&self, query: &Query) -> (Vec<Chain>, Option<&Priority>, Option<usize>) {
+        let name = query.name().to_ascii();
+        let names = [name.clone()];
+        let check_domains =
+            |rule: &RequestRule| domains_match(&self.domains, &rule.domains, &names);
+
         let check_type = |rule: &RequestRule| {
             rule.types
                 .as_ref()
@@ -169,86 +561,778 @@ impl Dispatcher {
                 .unwrap_or(true)
         };
 
+        let disabled = self.switches.request_rules.read();
         let rule = self
             .request_rules
             .iter()
-            .find(|r| check_domains(r) && check_type(r));
+            .enumerate()
+            .filter(|(index, _)| !disabled.contains(index))
+            .find(|(_, r)| check_domains(r) && check_type(r));
 
-        if let Some(rule) = rule {
+        if let Some((index, rule)) = rule {
             debug!(STDERR, "Query {} matches rule {:?}", name, rule);
-            rule.upstreams
-                .iter()
-                .filter_map(|u| self.resolvers.get(u).map(|v| (u.as_str(), v.clone())))
-                .collect()
+            metrics::REQUEST_RULE_HITS
+                .with_label_values(&[&index.to_string()])
+                .inc();
+            (
+                self.select_upstreams(&rule.upstreams),
+                rule.priority.as_ref(),
+                Some(index),
+            )
         } else {
             debug!(
                 STDERR,
                 "No dispatching rule matches for {}. Use defaults.", name
             );
             // If no dispatching rule matches, use defaults
-            self.defaults
-                .iter()
-                .filter_map(|u| self.resolvers.get(u).map(|v| (u.as_str(), v.clone())))
-                .collect()
+            metrics::REQUEST_RULE_HITS
+                .with_label_values(&["default"])
+                .inc();
+            (
+                self.select_upstreams(&self.defaults),
+                (*self.default_priority).as_ref(),
+                None,
+            )
         }
     }
 }
 
-impl Resolver for Dispatcher {
-    fn query(
+/// Upstreams tried one after another
+type Chain<'a> = Vec<(&'a str, MeasuredResolver)>;
+
+/// A hostname to resolve through the bootstrap upstream every interval
+type Resolution<'a> = (Name, &'a str, ResolvedAddrs, Duration);
+
+fn connector<'a>(
+    address: &'a UpstreamAddress,
+    options: &UpstreamOptions,
+    resolutions: &mut Vec<Resolution<'a>>,
+) -> Connector {
+    let connector = match address {
+        UpstreamAddress::Ip(addr) => Connector::new(Endpoint::Addr(*addr)),
+        UpstreamAddress::Name {
+            host,
+            port,
+            bootstrap,
+        } => {
+            let addrs = ResolvedAddrs::default();
+            match bootstrap {
+                Bootstrap::Static(ips) => *addrs.write() = ips.clone(),
+                Bootstrap::Upstream { name, interval } => {
+                    resolutions.push((host.clone(), name, addrs.clone(), *interval))
+                }
+                Bootstrap::Proxy => {}
+            }
+            Connector::new(Endpoint::Name {
+                host: host.to_string().trim_end_matches('.').to_owned(),
+                port: *port,
+                addrs,
+            })
+        }
+    };
+    connector
+        .with_proxy(options.proxy.clone())
+        .with_socket_options(options.socket.clone())
+}
+
+/// Upstreams and rules disabled at runtime
+#[derive(Default)]
+struct Switches {
+    upstreams: RwLock<HashSet<String>>,
+    request_rules: RwLock<HashSet<usize>>,
+    response_rules: RwLock<HashSet<usize>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpstreamStatus {
+    pub name: String,
+    pub enabled: bool,
+    pub healthy: bool,
+    /// Only available for TCP and TLS upstreams
+    pub connection: Option<&'static str>,
+    pub latency_ms: Option<f64>,
+}
+
+struct UpstreamGroup {
+    upstreams: Vec<String>,
+    strategy: Strategy,
+    // The next upstream to use for round-robin
+    next: AtomicUsize,
+}
+
+impl UpstreamGroup {
+    fn new(group: Group) -> Self {
+        UpstreamGroup {
+            upstreams: group.upstreams,
+            strategy: group.strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Dispatcher {
+    /// Resolves the query, recording what happens into the query log entry if given.
+    /// An identical query in flight is waited for instead of sending the query again.
+    fn resolve(
         &self,
         query: Query,
+        origin: Origin,
+        log: Option<LogEntry>,
+    ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
+        let (chains, priority, rule) = self.dispatch(&query);
+        if let Some(log) = &log {
+            log.lock().rule = rule;
+        }
+        let chains: Vec<Vec<_>> = chains
+            .into_iter()
+            .map(|chain| {
+                chain
+                    .into_iter()
+                    .map(|(name, resolver)| {
+                        let options = self.query_options(name, rule, &origin);
+                        (name.to_owned(), resolver, options)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let key = InFlightKey {
+            name: query.name().clone(),
+            query_type: query.query_type(),
+            query_class: query.query_class(),
+            rule,
+            upstreams: chains
+                .iter()
+                .flatten()
+                .map(|(name, _, options)| (name.clone(), options.clone()))
+                .collect(),
+        };
+        let mut in_flight = self.in_flight.lock();
+        if let Some(pending) = in_flight.get(&key) {
+            metrics::COALESCED_QUERIES.inc();
+            if let Some(log) = &log {
+                log.lock().coalesced = true;
+            }
+            return self.wait(pending.clone());
+        }
+
+        let preference = priority.map(|priority| Preference {
+            upstreams: priority.upstreams.clone(),
+            deadline: Instant::now() + priority.grace_period,
+            held: None,
+            pending: Arc::new(AtomicUsize::new(0)),
+        });
+        let tasks: Vec<_> = chains
+            .into_iter()
+            .map(|chain| {
+                let preferred = preference
+                    .as_ref()
+                    .filter(|p| chain.iter().any(|(name, _, _)| p.upstreams.contains(name)));
+                let task = query_in_turn(
+                    query.clone(),
+                    chain.into_iter(),
+                    self.validator.clone(),
+                    log.clone(),
+                );
+                match preferred {
+                    Some(preference) => {
+                        let pending = preference.pending.clone();
+                        pending.fetch_add(1, Ordering::Relaxed);
+                        Box::new(task.then(move |res| {
+                            pending.fetch_sub(1, Ordering::Relaxed);
+                            res
+                        })) as ChainResponse
+                    }
+                    None => task,
+                }
+            })
+            .collect();
+
+        let context = Context {
+            dispatcher: self.clone(),
+            domain: query.name().to_ascii(),
+            preference,
+            log,
+        };
+        let resolved = process_all(context, tasks);
+        let finished = {
+            let in_flight = self.in_flight.clone();
+            let key = key.clone();
+            Box::new(resolved.then(move |res| {
+                in_flight.lock().remove(&key);
+                res
+            })) as Box<Future<Item = DnsResponse, Error = ProtoError> + Send>
+        };
+        let shared = finished.shared();
+        in_flight.insert(key, shared.clone());
+        // Driven to the end even if every waiter gives up at its deadline,
+        // so that the entry is removed in time
+        tokio::spawn(shared.clone().then(|_| Ok(())));
+        self.wait(shared)
+    }
+
+    /// Waits for the query in flight, for no longer than the deadline of this query
+    fn wait(
+        &self,
+        shared: SharedResponse,
     ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
-        let resolvers = self.dispatch(&query);
-        let tasks: Vec<_> = resolvers
+        let resp = shared.then(unshare);
+        match self.deadline {
+            Some(deadline) => Box::new(Timeout::new(resp, deadline).map_err(|e| {
+                if e.is_elapsed() {
+                    ProtoErrorKind::Timeout.into()
+                } else if e.is_inner() {
+                    e.into_inner().expect("inner error")
+                } else {
+                    "Timer error".into()
+                }
+            })),
+            None => Box::new(resp),
+        }
+    }
+
+    /// The client subnet is decided by the dispatching rule, or the upstream if the rule
+    /// does not care. Nothing is sent by default.
+    fn query_options(&self, upstream: &str, rule: Option<usize>, origin: &Origin) -> QueryOptions {
+        let policy = rule
+            .and_then(|index| self.request_rules[index].ecs.as_ref())
+            .or_else(|| self.ecs.get(upstream));
+        QueryOptions {
+            client_subnet: policy.and_then(|policy| policy.apply(origin.addr, origin.subnet)),
+            dnssec: self.validator.is_some(),
+        }
+    }
+
+    /// Sends the query to every upstream chosen by the dispatching rules at once,
+    /// and checks each response against the response rules.
+    pub fn test(&self, query: Query) -> impl Future<Item = querylog::Entry, Error = ()> {
+        let (chains, _, rule) = self.dispatch(&query);
+        let mut entry = querylog::Entry::new(Ipv4Addr::LOCALHOST.into(), &query);
+        entry.rule = rule;
+        let origin = Origin {
+            addr: Some(Ipv4Addr::LOCALHOST.into()),
+            subnet: None,
+        };
+
+        let domain = query.name().to_ascii();
+        let tasks: Vec<_> = chains
             .into_iter()
+            .flatten()
             .map(|(name, resolver)| {
-                let domain = query.name().to_ascii();
-                let name1 = name.to_owned();
-                let name2 = name.to_owned();
-                resolver
-                    .query(query.clone())
-                    .map(move |resp| (domain, name1, resp))
-                    .map_err(move |e| (name2, e))
+                entry.upstreams.push(name.to_owned());
+                let name = name.to_owned();
+                let dispatcher = self.clone();
+                let domain = domain.clone();
+                let start = Instant::now();
+                let options = self.query_options(&name, rule, &origin);
+                let response = validated(resolver, query.clone(), options, self.validator.clone());
+                response.then(move |res| {
+                    let latency = start.elapsed();
+                    let outcome = match res {
+                        Ok((mut resp, dnssec)) => {
+                            let answers = resp
+                                .answers()
+                                .iter()
+                                .filter_map(|rec| rec.rdata().to_ip_addr())
+                                .collect();
+                            let (action, decider) =
+                                dispatcher.check_response(&domain, &name, &mut resp, dnssec);
+                            let result = match action {
+                                RuleAction::Drop => OutcomeResult::Drop,
+                                _ => OutcomeResult::Accept,
+                            };
+                            let mut outcome = querylog::Outcome::new(name, latency, result);
+                            outcome.answers = answers;
+                            decider.record(&mut outcome);
+                            outcome.dnssec = dnssec;
+                            outcome
+                        }
+                        Err(e) => {
+                            let mut outcome =
+                                querylog::Outcome::new(name, latency, OutcomeResult::Error);
+                            outcome.error = Some(e.to_string());
+                            outcome
+                        }
+                    };
+                    Ok::<_, ()>(outcome)
+                })
             })
             .collect();
 
-        fn process_all<A>(
-            dispatcher: Dispatcher,
-            tasks: Vec<A>, // responses that are not received yet
-        ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send>
-        where
-            A: Future<Item = (String, String, DnsResponse), Error = (String, ProtoError)>
-                + 'static
-                + Send,
-        {
-            if tasks.is_empty() {
-                Box::new(future::err("No response available".into()))
+        future::join_all(tasks).map(move |outcomes| {
+            entry.outcomes = outcomes;
+            entry
+        })
+    }
+}
+
+/// Allows the dispatcher to be replaced when the config is reloaded
+#[derive(Clone)]
+pub struct SharedDispatcher(Arc<RwLock<Dispatcher>>);
+
+impl SharedDispatcher {
+    pub fn new(dispatcher: Dispatcher) -> Self {
+        SharedDispatcher(Arc::new(RwLock::new(dispatcher)))
+    }
+
+    pub fn get(&self) -> Dispatcher {
+        self.0.read().clone()
+    }
+
+    pub fn replace(&self, dispatcher: Dispatcher) {
+        let old = mem::replace(&mut *self.0.write(), dispatcher);
+        old.retire();
+    }
+}
+
+impl RequestHandler for SharedDispatcher {
+    fn handle_request<R: ResponseHandler + 'static>(
+        &self,
+        request: &Request<'_>,
+        response_handle: R,
+    ) -> io::Result<()> {
+        self.get().handle_request(request, response_handle)
+    }
+}
+
+impl Resolver for Dispatcher {
+    fn query_with(
+        &self,
+        query: Query,
+        options: QueryOptions,
+    ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
+        let origin = Origin {
+            addr: None,
+            subnet: options.client_subnet,
+        };
+        self.resolve(query, origin, None)
+    }
+}
+
+/// Tells if any of `names` is inside the domain lists given by `patterns`.
+/// A pattern with a leading `!` matches if none of `names` is in the list.
+fn domains_match(
+    domains: &HashMap<String, Domains>,
+    patterns: &Option<Vec<String>>,
+    names: &[String],
+) -> bool {
+    patterns
+        .as_ref()
+        .map(|d| {
+            d.iter().any(|domains_pattern| {
+                // Process the leading `!`
+                let domains_tag = domains_pattern.trim_start_matches('!');
+                let toggle = (domains_pattern.len() - domains_tag.len()) % 2 == 1;
+
+                domains
+                    .get(domains_tag)
+                    .map(|domains| {
+                        names.iter().any(|name| domains.regex_set.is_match(name)) ^ toggle
+                    })
+                    .unwrap_or(false)
+            })
+        })
+        .unwrap_or(true) // No domains field means matching all domains
+}
+
+/// Tells if any address in `answers` is inside the ranges of `bogus`,
+/// which means the upstream returns its own page instead of NXDOMAIN
+fn is_bogus_nxdomain(
+    ranges: &HashMap<String, IpRange>,
+    bogus: &BogusNxdomain,
+    answers: &[Record],
+) -> bool {
+    answers
+        .iter()
+        .filter_map(|rec| rec.rdata().to_ip_addr())
+        .any(|ip| {
+            bogus.ranges.iter().any(|name| {
+                ranges
+                    .get(name)
+                    .map(|range| range.contains(ip))
+                    .unwrap_or(false)
+            })
+        })
+}
+
+/// Turns a bogus response from `upstream_name` into NXDOMAIN, or drops it.
+/// Returns `None` if the response is not bogus.
+fn check_bogus_nxdomain(
+    ranges: &HashMap<String, IpRange>,
+    bogus: &BogusNxdomain,
+    upstream_name: &str,
+    resp: &mut DnsResponse,
+) -> Option<RuleAction> {
+    if !is_bogus_nxdomain(ranges, bogus, resp.answers()) {
+        return None;
+    }
+    match bogus.action {
+        BogusNxdomainAction::Nxdomain => {
+            resp.take_answers();
+            resp.set_response_code(ResponseCode::NXDomain);
+            metrics::BOGUS_NXDOMAINS
+                .with_label_values(&[upstream_name])
+                .inc();
+            Some(RuleAction::Accept)
+        }
+        BogusNxdomainAction::Drop => {
+            metrics::RESPONSE_RULE_DROPS
+                .with_label_values(&["bogus-nxdomain", upstream_name])
+                .inc();
+            Some(RuleAction::Drop)
+        }
+    }
+}
+
+/// Removes the answers resolving `domain` to private addresses, unless it is
+/// in a domain list of the whitelist. Returns false if the response should be dropped.
+fn check_rebinding(
+    domains: &HashMap<String, Domains>,
+    protection: &RebindProtection,
+    domain: &str,
+    resp: &mut DnsResponse,
+) -> bool {
+    let whitelisted = protection.whitelist.iter().any(|tag| {
+        domains
+            .get(tag)
+            .map(|domains| domains.regex_set.is_match(domain))
+            .unwrap_or(false)
+    });
+    let is_private = |rec: &Record| {
+        rec.rdata()
+            .to_ip_addr()
+            .map(|ip| protection.ranges.contains(ip))
+            .unwrap_or(false)
+    };
+    if whitelisted || !resp.answers().iter().any(|rec| is_private(rec)) {
+        return true;
+    }
+    match protection.action {
+        RebindAction::Drop => false,
+        RebindAction::Strip => {
+            let mut answers = resp.take_answers();
+            answers.retain(|rec| !is_private(rec));
+            let has_address = answers.iter().any(|rec| rec.rdata().to_ip_addr().is_some());
+            resp.insert_answers(answers);
+            has_address
+        }
+    }
+}
+
+/// Where a query comes from
+struct Origin {
+    addr: Option<IpAddr>,
+    /// The EDNS Client Subnet option in the query
+    subnet: Option<ClientSubnet>,
+}
+
+type LogEntry = Arc<Mutex<querylog::Entry>>;
+
+type SharedResponse = future::Shared<Box<Future<Item = DnsResponse, Error = ProtoError> + Send>>;
+
+/// Queries are identical if they have the same question, are dispatched by the same rule
+/// and are sent to the same upstreams with the same options
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct InFlightKey {
+    name: Name,
+    query_type: RecordType,
+    query_class: DNSClass,
+    /// The priority of the upstreams comes with the rule
+    rule: Option<usize>,
+    upstreams: Vec<(String, QueryOptions)>,
+}
+
+/// What makes the decision on an upstream response
+#[derive(Clone, Copy, Debug)]
+enum Decider {
+    /// The response rule of the index
+    Rule(usize),
+    /// A check done before the response rules, named as in the metrics
+    Check(&'static str),
+    /// No rule matches, so the response is accepted
+    Default,
+}
+
+impl Decider {
+    fn record(self, outcome: &mut querylog::Outcome) {
+        match self {
+            Decider::Rule(index) => outcome.rule = Some(index),
+            Decider::Check(name) => outcome.check = Some(name),
+            Decider::Default => {}
+        }
+    }
+}
+
+/// Gives each query waiting for the same query in flight its own copy of the result
+fn unshare(
+    res: Result<future::SharedItem<DnsResponse>, future::SharedError<ProtoError>>,
+) -> Result<DnsResponse, ProtoError> {
+    match res {
+        Ok(resp) => Ok((*resp).clone()),
+        Err(e) => Err(match e.kind() {
+            ProtoErrorKind::Timeout => ProtoErrorKind::Timeout.into(),
+            _ => (*e).to_string().into(),
+        }),
+    }
+}
+
+/// A response from an upstream, not checked by the response rules yet
+struct UpstreamResponse {
+    upstream: String,
+    resp: DnsResponse,
+    dnssec: Option<Status>,
+    latency: Duration,
+}
+
+type ValidatedResponse =
+    Box<Future<Item = (DnsResponse, Option<Status>), Error = ProtoError> + 'static + Send>;
+
+/// Queries the upstream and validates the response if DNSSEC validation is enabled.
+/// The keys needed for validation are queried from the same upstream.
+fn validated(
+    resolver: MeasuredResolver,
+    query: Query,
+    options: QueryOptions,
+    validator: Option<Validator>,
+) -> ValidatedResponse {
+    let response = resolver.query_with(query, options);
+    match validator {
+        Some(validator) => Box::new(
+            response
+                .and_then(move |resp| validator.validate(resolver, resp))
+                .map(|(resp, status)| (resp, Some(status))),
+        ),
+        None => Box::new(response.map(|resp| (resp, None))),
+    }
+}
+
+type ChainResponse =
+    Box<Future<Item = UpstreamResponse, Error = (String, ProtoError)> + 'static + Send>;
+
+/// Queries the upstreams one after another until one of them succeeds
+fn query_in_turn(
+    query: Query,
+    mut chain: std::vec::IntoIter<(String, MeasuredResolver, QueryOptions)>,
+    validator: Option<Validator>,
+    log: Option<LogEntry>,
+) -> ChainResponse {
+    let (name, resolver, options) = chain.next().expect("Empty upstream chain");
+    if let Some(log) = &log {
+        log.lock().upstreams.push(name.clone());
+    }
+    let start = Instant::now();
+    let response = validated(resolver, query.clone(), options, validator.clone());
+    Box::new(response.then(move |res| match res {
+        Ok((resp, dnssec)) => {
+            let latency = start.elapsed();
+            metrics::UPSTREAM_LATENCY
+                .with_label_values(&[&name])
+                .observe(latency.as_secs() as f64 + f64::from(latency.subsec_nanos()) / 1e9);
+            Box::new(future::ok(UpstreamResponse {
+                upstream: name,
+                resp,
+                dnssec,
+                latency,
+            })) as ChainResponse
+        }
+        Err(e) => {
+            let counter = match e.kind() {
+                ProtoErrorKind::Timeout => &metrics::UPSTREAM_TIMEOUTS,
+                _ => &metrics::UPSTREAM_ERRORS,
+            };
+            counter.with_label_values(&[&name]).inc();
+            if let Some(log) = &log {
+                let mut outcome =
+                    querylog::Outcome::new(name.clone(), start.elapsed(), OutcomeResult::Error);
+                outcome.error = Some(e.to_string());
+                log.lock().outcomes.push(outcome);
+            }
+            if chain.as_slice().is_empty() {
+                Box::new(future::err((name, e)))
             } else {
-                let tasks = future::select_all(tasks);
-                Box::new(tasks.then(|res| match res {
-                    Ok(((domain, name, resp), _, remaining)) => {
-                        match dispatcher.check_response(&domain, &name, &resp) {
-                            RuleAction::Accept => {
-                                // Ignore the remaining future
-                                tokio::spawn(
-                                    future::join_all(remaining).map(|_| ()).map_err(|_| ()),
-                                );
-                                debug!(STDERR, "Use result from {}", name);
-                                Box::new(future::ok(resp))
+                error!(STDERR, "{}: {}. Try the next upstream.", name, e);
+                query_in_turn(query, chain, validator, log)
+            }
+        }
+    }))
+}
+
+/// The state of a query shared by the steps of processing responses
+struct Context {
+    dispatcher: Dispatcher,
+    domain: String,
+    preference: Option<Preference>,
+    log: Option<LogEntry>,
+}
+
+impl Context {
+    /// Returns `resp` from `upstream` to the client
+    fn select(
+        self,
+        upstream: String,
+        resp: DnsResponse,
+    ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
+        metrics::UPSTREAM_WINS.with_label_values(&[&upstream]).inc();
+        if let Some(log) = &self.log {
+            log.lock().selected = Some(upstream);
+        }
+        Box::new(future::ok(resp))
+    }
+}
+
+/// An acceptable response from an upstream other than the preferred ones
+/// is held until the deadline, unless a preferred upstream answers before that.
+/// It is released early if all the preferred upstreams fail or are dropped.
+struct Preference {
+    upstreams: Vec<String>,
+    deadline: Instant,
+    held: Option<(String, DnsResponse)>,
+    /// The number of chains with preferred upstreams whose results are not received yet
+    pending: Arc<AtomicUsize>,
+}
+
+type Selected<A> = Result<(UpstreamResponse, usize, Vec<A>), ((String, ProtoError), usize, Vec<A>)>;
+
+fn process_all<A>(
+    mut context: Context,
+    tasks: Vec<A>, // responses that are not received yet
+) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send>
+where
+    A: Future<Item = UpstreamResponse, Error = (String, ProtoError)> + 'static + Send,
+{
+    // Nothing is worth waiting for once no preferred upstream can answer
+    let preferred_pending = context
+        .preference
+        .as_ref()
+        .map(|p| p.pending.load(Ordering::Relaxed) > 0)
+        .unwrap_or(false);
+    if tasks.is_empty() || !preferred_pending {
+        if let Some((name, resp)) = context.preference.take().and_then(|p| p.held) {
+            // Ignore the remaining future
+            tokio::spawn(future::join_all(tasks).map(|_| ()).map_err(|_| ()));
+            debug!(STDERR, "Use held result from {}", name);
+            return context.select(name, resp);
+        }
+        if tasks.is_empty() {
+            return Box::new(future::err("No response available".into()));
+        }
+    }
+
+    let tasks = future::select_all(tasks);
+    match context.preference.take() {
+        Some(Preference {
+            upstreams,
+            deadline,
+            held: Some(held),
+            pending,
+        }) => {
+            // Wait for the preferred upstreams until the grace period ends
+            Box::new(tasks.select2(Delay::new(deadline)).then(move |res| {
+                let res = match res {
+                    Ok(future::Either::A((res, _))) => Ok(res),
+                    Err(future::Either::A((res, _))) => Err(res),
+                    Ok(future::Either::B((_, tasks))) | Err(future::Either::B((_, tasks))) => {
+                        // Ignore the remaining future
+                        tokio::spawn(tasks.then(|res| {
+                            let remaining = match res {
+                                Ok((_, _, remaining)) | Err((_, _, remaining)) => remaining,
+                            };
+                            future::join_all(remaining).map(|_| ()).map_err(|_| ())
+                        }));
+                        let (name, resp) = held;
+                        debug!(STDERR, "Grace period is over. Use result from {}", name);
+                        return context.select(name, resp);
+                    }
+                };
+                context.preference = Some(Preference {
+                    upstreams,
+                    deadline,
+                    held: Some(held),
+                    pending,
+                });
+                process_one(context, res)
+            }))
+        }
+        preference => {
+            context.preference = preference;
+            Box::new(tasks.then(move |res| process_one(context, res)))
+        }
+    }
+}
+
+fn process_one<A>(
+    mut context: Context,
+    res: Selected<A>,
+) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send>
+where
+    A: Future<Item = UpstreamResponse, Error = (String, ProtoError)> + 'static + Send,
+{
+    match res {
+        Ok((
+            UpstreamResponse {
+                upstream: name,
+                mut resp,
+                dnssec,
+                latency,
+            },
+            _,
+            remaining,
+        )) => {
+            let answers: Vec<_> = context
+                .log
+                .as_ref()
+                .map(|_| {
+                    resp.answers()
+                        .iter()
+                        .filter_map(|rec| rec.rdata().to_ip_addr())
+                        .collect()
+                })
+                .unwrap_or_default();
+            let (action, decider) =
+                context
+                    .dispatcher
+                    .check_response(&context.domain, &name, &mut resp, dnssec);
+            if let Some(log) = &context.log {
+                let result = match action {
+                    RuleAction::Drop => OutcomeResult::Drop,
+                    _ => OutcomeResult::Accept,
+                };
+                let mut outcome = querylog::Outcome::new(name.clone(), latency, result);
+                outcome.answers = answers;
+                decider.record(&mut outcome);
+                outcome.dnssec = dnssec;
+                log.lock().outcomes.push(outcome);
+            }
+
+            match action {
+                RuleAction::Drop => process_all(context, remaining),
+                _ => {
+                    let should_hold = context
+                        .preference
+                        .as_ref()
+                        .map(|p| !p.upstreams.contains(&name) && Instant::now() < p.deadline)
+                        .unwrap_or(false);
+                    if should_hold {
+                        if let Some(preference) = &mut context.preference {
+                            if preference.held.is_none() {
+                                debug!(STDERR, "Hold result from {}", name);
+                                preference.held = Some((name, resp));
                             }
-                            RuleAction::Drop => process_all(dispatcher, remaining),
                         }
+                        process_all(context, remaining)
+                    } else {
+                        // Ignore the remaining future
+                        tokio::spawn(future::join_all(remaining).map(|_| ()).map_err(|_| ()));
+                        debug!(STDERR, "Use result from {}", name);
+                        context.select(name, resp)
                     }
-                    Err(((name, e), _, remaining)) => {
-                        error!(STDERR, "{}: {}", name, e);
-                        process_all(dispatcher, remaining)
-                    }
-                }))
+                }
             }
         }
-
-        process_all(self.clone(), tasks)
+        Err(((name, e), _, remaining)) => {
+            error!(STDERR, "{}: {}", name, e);
+            process_all(context, remaining)
+        }
     }
 }
 
@@ -269,19 +1353,64 @@ impl RequestHandler for Dispatcher {
             .map(|q| q.original().clone())
             .next();
 
+        if let Some(query) = &query {
+            metrics::QUERIES
+                .with_label_values(&[
+                    &query.query_type().to_string(),
+                    &request.src.ip().to_string(),
+                ])
+                .inc();
+        }
+
         // Save raw query bytes. This will be copied to the question section of the response.
         let query_bytes = query.clone().map(|q| q.to_bytes());
 
+        let start = Instant::now();
+        let log = match (&self.query_log, &query) {
+            (Some(_), Some(query)) => Some(Arc::new(Mutex::new(querylog::Entry::new(
+                request.src.ip(),
+                query,
+            )))),
+            _ => None,
+        };
+        let query_log = self.query_log.clone();
+
+        let query_time = SystemTime::now();
+        let client = request.src;
+        let server = self.bind;
+        let tapped_query = match (&self.dnstap, &query) {
+            (Some(dnstap), Some(query)) => {
+                let mut message = DnstapMessage::new(MessageKind::ClientQuery, SocketProtocol::Udp);
+                message.query_address = Some(client);
+                message.response_address = Some(server);
+                message.query_time = Some(query_time);
+                message.query_message = dnstap::query_message(request.message.id(), query);
+                dnstap.send(&message);
+                Some((dnstap.clone(), query.clone(), message.query_message))
+            }
+            _ => None,
+        };
+
+        let origin = Origin {
+            addr: Some(client.ip()),
+            subnet: request
+                .message
+                .edns()
+                .and_then(|edns| edns.option(&EdnsCode::from(EDNS_CLIENT_SUBNET)))
+                .and_then(ClientSubnet::from_option),
+        };
+
         // Query for result
         let dispatcher = self.clone();
-        let result_future =
-            future::lazy(move || query.map(move |q| dispatcher.query(q))).then(|res| match res {
-                Ok(resp) => Ok(resp),
-                Err(e) => {
-                    error!(STDERR, "Resolve error: {}", e);
-                    Ok(None)
-                }
-            });
+        let log2 = log.clone();
+        let resolve = move |q| dispatcher.resolve(q, origin, log2);
+        let result_future = future::lazy(move || query.map(resolve)).then(|res| match res {
+            Ok(resp) => Ok(resp),
+            Err(e) => {
+                error!(STDERR, "Resolve error: {}", e);
+                Ok(None)
+            }
+        });
 
         // Build response header
         let id = request.message.id();
@@ -300,8 +1429,13 @@ impl RequestHandler for Dispatcher {
                 }))?;
                 let mut builder = MessageResponseBuilder::new(queries.as_ref());
 
+                let rcode = resp
+                    .as_ref()
+                    .map(|resp| resp.response_code())
+                    .unwrap_or(ResponseCode::ServFail);
                 let message = if let Some(ref resp) = resp {
                     // Put answers into the response
+                    header.set_response_code(rcode);
                     let answers = resp.answers();
                     builder.answers(AuthLookup::Records(LookupRecords::RecordsIter(
                         RrsetRecords::RecordsOnly(answers.iter()),
@@ -309,9 +1443,32 @@ impl RequestHandler for Dispatcher {
                     builder.build(header)
                 } else {
                     // No answer available (Usually due to bad network condition)
+                    metrics::SERVFAILS.inc();
                     builder.error_msg(id, op_code, ResponseCode::ServFail)
                 };
 
+                if let (Some(query_log), Some(log)) = (query_log, log) {
+                    let mut entry = log.lock();
+                    entry.finish(rcode, start.elapsed());
+                    query_log.write(&entry);
+                }
+
+                if let Some((dnstap, query, query_message)) = tapped_query {
+                    let answers = match &resp {
+                        Some(resp) => resp.answers(),
+                        None => &[][..],
+                    };
+                    let mut message =
+                        DnstapMessage::new(MessageKind::ClientResponse, SocketProtocol::Udp);
+                    message.query_address = Some(client);
+                    message.response_address = Some(server);
+                    message.query_time = Some(query_time);
+                    message.query_message = query_message;
+                    message.response_time = Some(SystemTime::now());
+                    message.response_message = dnstap::response_message(id, &query, answers, rcode);
+                    dnstap.send(&message);
+                }
+
                 Ok(response_handle.send_response(message)?)
             })
             .map_err(|e: ProtoError| error!(STDERR, "{}", e));
@@ -320,3 +1477,291 @@ impl RequestHandler for Dispatcher {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigBuilder;
+    use std::net::UdpSocket;
+    use std::thread;
+    use tokio::runtime::Runtime;
+    use trust_dns_proto::op::Message;
+
+    /// An upstream answering every query with `answer` after a while,
+    /// counting the queries it receives
+    fn fake_upstream(answer: IpAddr) -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok((len, src)) = socket.recv_from(&mut buf) {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut msg = Message::from_vec(&buf[..len]).unwrap();
+                let name = msg.queries()[0].name().clone();
+                let rdata = match answer {
+                    IpAddr::V4(ip) => RData::A(ip),
+                    IpAddr::V6(ip) => RData::AAAA(ip),
+                };
+                let rtype = rdata.to_record_type();
+                msg.set_message_type(MessageType::Response);
+                msg.add_answer(Record::from_rdata(name, 60, rtype, rdata));
+                let socket = socket.try_clone().unwrap();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(200));
+                    let _ = socket.send_to(&msg.to_vec().unwrap(), src);
+                });
+            }
+        });
+        (addr, received)
+    }
+
+    /// A dispatcher with the fake upstream as the default one, which sends
+    /// the client subnet. `extra` is appended to the config.
+    fn dispatcher(runtime: &mut Runtime, upstream: SocketAddr, extra: &str) -> Dispatcher {
+        let config = format!(
+            r#"
+            bind = "127.0.0.1:0"
+            {}
+            [upstreams.fake]
+            address = "{}"
+            network = "udp"
+            default = true
+            ecs = "client"
+            "#,
+            extra, upstream
+        );
+        let builder: ConfigBuilder = toml::from_str(&config).unwrap();
+        let config = builder.build().unwrap();
+        // The upstreams spawn their background tasks
+        runtime
+            .block_on(future::lazy(|| Dispatcher::new(config, None, None)))
+            .unwrap()
+    }
+
+    fn origin(addr: &str) -> Origin {
+        Origin {
+            addr: Some(addr.parse().unwrap()),
+            subnet: None,
+        }
+    }
+
+    /// Resolves the queries at the same time
+    fn resolve_all(
+        runtime: &mut Runtime,
+        dispatcher: &Dispatcher,
+        queries: Vec<(Query, Origin)>,
+    ) -> Vec<DnsResponse> {
+        let dispatcher = dispatcher.clone();
+        runtime
+            .block_on(future::lazy(move || {
+                future::join_all(
+                    queries
+                        .into_iter()
+                        .map(|(query, origin)| dispatcher.resolve(query, origin, None))
+                        .collect::<Vec<_>>(),
+                )
+            }))
+            .unwrap()
+    }
+
+    fn query(name: &str) -> Query {
+        Query::query(Name::from_ascii(name).unwrap(), RecordType::A)
+    }
+
+    #[test]
+    fn coalesce_identical_queries() {
+        let mut runtime = Runtime::new().unwrap();
+        let (addr, received) = fake_upstream("203.0.113.1".parse().unwrap());
+        let dispatcher = dispatcher(&mut runtime, addr, "");
+
+        let resps = resolve_all(
+            &mut runtime,
+            &dispatcher,
+            vec![
+                (query("example.com."), origin("203.0.113.7")),
+                (query("example.com."), origin("203.0.113.9")),
+            ],
+        );
+        assert_eq!(resps.len(), 2);
+        assert!(resps.iter().all(|resp| resp.answers().len() == 1));
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn keep_different_queries_apart() {
+        let mut runtime = Runtime::new().unwrap();
+        let (addr, received) = fake_upstream("203.0.113.1".parse().unwrap());
+        let dispatcher = dispatcher(&mut runtime, addr, "");
+
+        let mut chaos = query("example.com.");
+        chaos.set_query_class(DNSClass::CH);
+        resolve_all(
+            &mut runtime,
+            &dispatcher,
+            vec![
+                (query("example.com."), origin("203.0.113.7")),
+                (chaos, origin("203.0.113.7")),
+            ],
+        );
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+
+        // Sent with different client subnets
+        resolve_all(
+            &mut runtime,
+            &dispatcher,
+            vec![
+                (query("example.com."), origin("203.0.113.7")),
+                (query("example.com."), origin("198.51.100.7")),
+            ],
+        );
+        assert_eq!(received.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn negated_domain_lists() {
+        let config = r#"
+            bind = "127.0.0.1:0"
+            [upstreams.fake]
+            address = "127.0.0.1:53"
+            network = "udp"
+            default = true
+            [domains.poisoned]
+            list = ["twitter.com"]
+            [[requests]]
+            domains = ["!poisoned"]
+            upstreams = ["fake"]
+            [[responses]]
+            domains = ["!poisoned"]
+            action = "drop"
+        "#;
+        let builder: ConfigBuilder = toml::from_str(config).unwrap();
+        let config = builder.build().unwrap();
+        let matches = |patterns: &Option<Vec<String>>, name: &str| {
+            domains_match(&config.domains, patterns, &[name.to_owned()])
+        };
+        for patterns in &[
+            &config.request_rules[0].domains,
+            &config.response_rules[0].domains,
+        ] {
+            assert!(matches(patterns, "example.com"));
+            assert!(!matches(patterns, "twitter.com"));
+        }
+        // A double negation cancels out
+        let twice = Some(vec!["!!poisoned".to_owned()]);
+        assert!(matches(&twice, "twitter.com"));
+        assert!(!matches(&twice, "example.com"));
+    }
+
+    fn response(addrs: &[&str]) -> DnsResponse {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let mut msg = Message::new();
+        msg.set_message_type(MessageType::Response);
+        for addr in addrs {
+            let rdata = match addr.parse().unwrap() {
+                IpAddr::V4(ip) => RData::A(ip),
+                IpAddr::V6(ip) => RData::AAAA(ip),
+            };
+            let rtype = rdata.to_record_type();
+            msg.add_answer(Record::from_rdata(name.clone(), 60, rtype, rdata));
+        }
+        DnsResponse::from(msg)
+    }
+
+    /// The config with only the default upstream and `extra`,
+    /// not starting any resolver
+    fn config(extra: &str) -> Config {
+        let config = format!(
+            r#"
+            bind = "127.0.0.1:0"
+            {}
+            [upstreams.fake]
+            address = "127.0.0.1:53"
+            network = "udp"
+            default = true
+            "#,
+            extra
+        );
+        let builder: ConfigBuilder = toml::from_str(&config).unwrap();
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn strip_rebinding_answers() {
+        let config = config(
+            r#"
+            [rebind-protection]
+            whitelist = ["lan"]
+            [domains.lan]
+            list = ["home.arpa"]
+            "#,
+        );
+        let protection = config.rebind_protection.as_ref().unwrap();
+        let check = |domain: &str, resp: &mut DnsResponse| {
+            check_rebinding(&config.domains, protection, domain, resp)
+        };
+
+        // Whitelisted
+        let mut resp = response(&["192.168.1.1", "203.0.113.1"]);
+        assert!(check("nas.home.arpa", &mut resp));
+        assert_eq!(resp.answers().len(), 2);
+
+        // Outside the whitelist
+        let mut resp = response(&["192.168.1.1", "203.0.113.1"]);
+        assert!(check("example.com", &mut resp));
+        assert_eq!(resp.answers().len(), 1);
+        assert_eq!(
+            resp.answers()[0].rdata().to_ip_addr(),
+            Some("203.0.113.1".parse().unwrap())
+        );
+
+        // Nothing is left after stripping
+        let mut resp = response(&["192.168.1.1", "::ffff:10.0.0.1"]);
+        assert!(!check("example.com", &mut resp));
+    }
+
+    fn bogus_config(action: &str) -> Config {
+        config(&format!(
+            r#"
+            [bogus-nxdomain]
+            ranges = ["ads"]
+            action = "{}"
+            [ranges.ads]
+            list = ["203.0.113.0/24"]
+            "#,
+            action
+        ))
+    }
+
+    #[test]
+    fn bogus_nxdomain_ranges() {
+        let config = bogus_config("nxdomain");
+        let bogus = config.bogus_nxdomain.as_ref().unwrap();
+        let is_bogus =
+            |addrs: &[&str]| is_bogus_nxdomain(&config.ranges, bogus, response(addrs).answers());
+
+        assert!(is_bogus(&["203.0.113.1"]));
+        assert!(is_bogus(&["198.51.100.1", "203.0.113.1"]));
+        assert!(!is_bogus(&["198.51.100.1"]));
+        assert!(!is_bogus(&[]));
+    }
+
+    #[test]
+    fn bogus_nxdomain_conversion() {
+        let config = bogus_config("nxdomain");
+        let bogus = config.bogus_nxdomain.as_ref().unwrap();
+        let mut resp = response(&["203.0.113.1"]);
+        let action = check_bogus_nxdomain(&config.ranges, bogus, "fake", &mut resp);
+        assert_eq!(action, Some(RuleAction::Accept));
+        assert_eq!(resp.response_code(), ResponseCode::NXDomain);
+        assert!(resp.answers().is_empty());
+
+        let config = bogus_config("drop");
+        let bogus = config.bogus_nxdomain.as_ref().unwrap();
+        let mut resp = response(&["203.0.113.1"]);
+        let action = check_bogus_nxdomain(&config.ranges, bogus, "fake", &mut resp);
+        assert_eq!(action, Some(RuleAction::Drop));
+        assert_eq!(resp.answers().len(), 1);
+    }
+}
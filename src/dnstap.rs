@@ -0,0 +1,496 @@
+//! dnstap output over Frame Streams.
+//! See http://dnstap.info/ and https://github.com/farsightsec/fstrm for the formats.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{DnstapOutput, DnstapTarget};
+use crate::resolver::{QueryOptions, Resolver};
+use crate::STDERR;
+
+use failure::Error;
+use parking_lot::Mutex;
+use slog::{error, warn};
+use tokio::prelude::*;
+use trust_dns::op::{DnsResponse, Message, Query};
+use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::op::{MessageType, OpCode, ResponseCode};
+use trust_dns_proto::rr::Record;
+
+/// Frames waiting to be written. New frames are discarded if the writer can't keep up.
+const QUEUE_SIZE: usize = 4096;
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// Frame Streams control frame types
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+
+#[derive(Debug, Clone, Copy)]
+pub enum MessageKind {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SocketProtocol {
+    Udp = 1,
+    Tcp = 2,
+    Dot = 3,
+    DnscryptUdp = 5,
+}
+
+/// A dnstap message. Addresses are those of the query initiator and the responder.
+#[derive(Debug)]
+pub struct DnstapMessage {
+    pub kind: MessageKind,
+    pub protocol: SocketProtocol,
+    pub query_address: Option<SocketAddr>,
+    pub response_address: Option<SocketAddr>,
+    pub query_time: Option<SystemTime>,
+    pub query_message: Option<Vec<u8>>,
+    pub response_time: Option<SystemTime>,
+    pub response_message: Option<Vec<u8>>,
+}
+
+impl DnstapMessage {
+    pub fn new(kind: MessageKind, protocol: SocketProtocol) -> Self {
+        DnstapMessage {
+            kind,
+            protocol,
+            query_address: None,
+            response_address: None,
+            query_time: None,
+            query_message: None,
+            response_time: None,
+            response_message: None,
+        }
+    }
+
+    /// Encodes the message wrapped in a `Dnstap` protobuf message
+    fn encode(&self, identity: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        encode_varint_field(&mut message, 1, self.kind as u64);
+        let family =
+            self.query_address
+                .or(self.response_address)
+                .map(|addr| if addr.is_ipv4() { 1 } else { 2 });
+        if let Some(family) = family {
+            encode_varint_field(&mut message, 2, family);
+        }
+        encode_varint_field(&mut message, 3, self.protocol as u64);
+        if let Some(addr) = self.query_address {
+            encode_bytes_field(&mut message, 4, &ip_bytes(addr.ip()));
+            encode_varint_field(&mut message, 6, u64::from(addr.port()));
+        }
+        if let Some(addr) = self.response_address {
+            encode_bytes_field(&mut message, 5, &ip_bytes(addr.ip()));
+            encode_varint_field(&mut message, 7, u64::from(addr.port()));
+        }
+        if let Some(time) = self.query_time {
+            encode_time_fields(&mut message, 8, 9, time);
+        }
+        if let Some(query) = &self.query_message {
+            encode_bytes_field(&mut message, 10, query);
+        }
+        if let Some(time) = self.response_time {
+            encode_time_fields(&mut message, 12, 13, time);
+        }
+        if let Some(response) = &self.response_message {
+            encode_bytes_field(&mut message, 14, response);
+        }
+
+        let mut dnstap = Vec::new();
+        if !identity.is_empty() {
+            encode_bytes_field(&mut dnstap, 1, identity);
+        }
+        encode_bytes_field(
+            &mut dnstap,
+            2,
+            concat!("yadd ", env!("CARGO_PKG_VERSION")).as_bytes(),
+        );
+        encode_bytes_field(&mut dnstap, 14, &message);
+        // Type MESSAGE
+        encode_varint_field(&mut dnstap, 15, 1);
+        dnstap
+    }
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn encode_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn encode_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    encode_varint(buf, field << 3);
+    encode_varint(buf, value);
+}
+
+fn encode_bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    encode_varint(buf, field << 3 | 2);
+    encode_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+fn encode_time_fields(buf: &mut Vec<u8>, sec_field: u64, nsec_field: u64, time: SystemTime) {
+    let since_epoch = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    encode_varint_field(buf, sec_field, since_epoch.as_secs());
+    // fixed32
+    encode_varint(buf, nsec_field << 3 | 5);
+    let nanos = since_epoch.subsec_nanos();
+    buf.extend_from_slice(&[
+        nanos as u8,
+        (nanos >> 8) as u8,
+        (nanos >> 16) as u8,
+        (nanos >> 24) as u8,
+    ]);
+}
+
+fn put_be_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&[
+        (value >> 24) as u8,
+        (value >> 16) as u8,
+        (value >> 8) as u8,
+        value as u8,
+    ]);
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |n, &b| n << 8 | u32::from(b))
+}
+
+/// The STOP frame carries no content type
+fn control_frame(control_type: u32) -> Vec<u8> {
+    let mut payload = Vec::new();
+    put_be_u32(&mut payload, control_type);
+    if control_type != CONTROL_STOP {
+        put_be_u32(&mut payload, CONTROL_FIELD_CONTENT_TYPE);
+        put_be_u32(&mut payload, CONTENT_TYPE.len() as u32);
+        payload.extend_from_slice(CONTENT_TYPE);
+    }
+
+    // An escape sequence of zero length tells it is a control frame
+    let mut frame = vec![0; 4];
+    put_be_u32(&mut frame, payload.len() as u32);
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Returns the type of the control frame read from `reader`
+fn read_control_frame<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+    let mut payload = vec![0; be_u32(&header[4..]) as usize];
+    reader.read_exact(&mut payload)?;
+    if header[..4] != [0; 4] || payload.len() < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid dnstap control frame",
+        ));
+    }
+    Ok(be_u32(&payload[..4]))
+}
+
+/// Builds the wire format of a query sent to an upstream or received from a client
+pub fn query_message(id: u16, query: &Query) -> Option<Vec<u8>> {
+    let mut message = Message::new();
+    message
+        .set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(query.clone());
+    message.to_vec().ok()
+}
+
+/// Builds the wire format of a response returned to a client
+pub fn response_message(
+    id: u16,
+    query: &Query,
+    answers: &[Record],
+    rcode: ResponseCode,
+) -> Option<Vec<u8>> {
+    let mut message = Message::new();
+    message
+        .set_id(id)
+        .set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .set_recursion_available(true)
+        .set_response_code(rcode)
+        .add_query(query.clone());
+    message.insert_answers(answers.to_vec());
+    message.to_vec().ok()
+}
+
+/// Writes dnstap frames in a background thread
+pub struct Dnstap {
+    // An empty frame asks the writer to end the stream
+    sender: SyncSender<Vec<u8>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    identity: Vec<u8>,
+}
+
+impl Dnstap {
+    pub fn new(output: &DnstapOutput) -> Result<Self, Error> {
+        let target = output.target.clone();
+        // Fail early if the output is not available at all
+        let writer = open(&target)?;
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let handle = thread::Builder::new()
+            .name("dnstap".to_owned())
+            .spawn(move || write_all(target, writer, receiver))?;
+        Ok(Dnstap {
+            sender,
+            writer: Mutex::new(Some(handle)),
+            identity: output.identity.clone().into_bytes(),
+        })
+    }
+
+    pub fn send(&self, message: &DnstapMessage) {
+        let payload = message.encode(&self.identity);
+        let mut frame = Vec::with_capacity(4 + payload.len());
+        put_be_u32(&mut frame, payload.len() as u32);
+        frame.extend_from_slice(&payload);
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(frame) {
+            warn!(STDERR, "dnstap queue is full. Frame discarded.");
+        }
+    }
+
+    /// Writes the queued frames and ends the stream with a STOP frame.
+    /// Frames sent after that are discarded.
+    pub fn finish(&self) {
+        if let Some(handle) = self.writer.lock().take() {
+            if self.sender.send(Vec::new()).is_ok() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+enum Writer {
+    File(BufWriter<File>),
+    #[cfg(unix)]
+    Socket(std::os::unix::net::UnixStream),
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::File(file) => file.write(buf),
+            #[cfg(unix)]
+            Writer::Socket(socket) => socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::File(file) => file.flush(),
+            #[cfg(unix)]
+            Writer::Socket(socket) => socket.flush(),
+        }
+    }
+}
+
+impl Writer {
+    /// Writes the STOP frame. A receiver on a socket acknowledges it with FINISH.
+    fn stop(&mut self) -> io::Result<()> {
+        self.write_all(&control_frame(CONTROL_STOP))?;
+        self.flush()?;
+        #[cfg(unix)]
+        {
+            if let Writer::Socket(socket) = self {
+                socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+                read_control_frame(socket)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open(target: &DnstapTarget) -> io::Result<Writer> {
+    match target {
+        DnstapTarget::File(path) => {
+            let mut file = BufWriter::new(File::create(path)?);
+            file.write_all(&control_frame(CONTROL_START))?;
+            Ok(Writer::File(file))
+        }
+        DnstapTarget::Socket(path) => connect(path),
+    }
+}
+
+#[cfg(unix)]
+fn connect(path: &str) -> io::Result<Writer> {
+    // Bidirectional handshake
+    let mut socket = std::os::unix::net::UnixStream::connect(path)?;
+    socket.write_all(&control_frame(CONTROL_READY))?;
+    if read_control_frame(&mut socket)? != CONTROL_ACCEPT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "dnstap receiver does not accept",
+        ));
+    }
+
+    socket.write_all(&control_frame(CONTROL_START))?;
+    Ok(Writer::Socket(socket))
+}
+
+#[cfg(not(unix))]
+fn connect(_path: &str) -> io::Result<Writer> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Unix sockets are not supported on this platform",
+    ))
+}
+
+fn write_all(target: DnstapTarget, writer: Writer, receiver: Receiver<Vec<u8>>) {
+    let mut writer = Some(writer);
+    // A file is not reopened after a failure, which would truncate what is captured
+    let reconnect = match target {
+        DnstapTarget::File(_) => false,
+        DnstapTarget::Socket(_) => true,
+    };
+    for frame in receiver {
+        if frame.is_empty() {
+            break;
+        }
+        if writer.is_none() && reconnect {
+            // Reconnect if the socket is closed
+            writer = open(&target)
+                .map_err(|e| error!(STDERR, "Unable to open dnstap output: {}", e))
+                .ok();
+        }
+        if let Some(w) = &mut writer {
+            if let Err(e) = w.write_all(&frame).and_then(|()| w.flush()) {
+                error!(STDERR, "Unable to write dnstap frame: {}", e);
+                if !reconnect {
+                    error!(STDERR, "dnstap output to the file is stopped");
+                }
+                writer = None;
+            }
+        }
+    }
+    if let Some(w) = &mut writer {
+        if let Err(e) = w.stop() {
+            error!(STDERR, "Unable to stop dnstap output: {}", e);
+        }
+    }
+}
+
+/// Wraps an upstream resolver and emits FORWARDER_QUERY and FORWARDER_RESPONSE messages
+pub struct DnstapResolver {
+    inner: Arc<Resolver>,
+    dnstap: Arc<Dnstap>,
+    // Unknown for upstreams given by hostnames
+    address: Option<SocketAddr>,
+    protocol: SocketProtocol,
+}
+
+impl DnstapResolver {
+    pub fn new(
+        inner: Arc<Resolver>,
+        dnstap: Arc<Dnstap>,
+        address: Option<SocketAddr>,
+        protocol: SocketProtocol,
+    ) -> Self {
+        DnstapResolver {
+            inner,
+            dnstap,
+            address,
+            protocol,
+        }
+    }
+}
+
+impl Resolver for DnstapResolver {
+    fn query_with(
+        &self,
+        query: Query,
+        options: QueryOptions,
+    ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
+        let query_time = SystemTime::now();
+        let query_bytes = query_message(0, &query);
+
+        let mut message = DnstapMessage::new(MessageKind::ForwarderQuery, self.protocol);
+        message.response_address = self.address;
+        message.query_time = Some(query_time);
+        message.query_message = query_bytes.clone();
+        self.dnstap.send(&message);
+
+        let dnstap = self.dnstap.clone();
+        let address = self.address;
+        let protocol = self.protocol;
+        Box::new(self.inner.query_with(query, options).map(move |resp| {
+            let mut message = DnstapMessage::new(MessageKind::ForwarderResponse, protocol);
+            message.response_address = address;
+            message.query_time = Some(query_time);
+            message.query_message = query_bytes;
+            message.response_time = Some(SystemTime::now());
+            message.response_message = resp.to_vec().ok();
+            dnstap.send(&message);
+            resp
+        }))
+    }
+
+    fn connection_state(&self) -> Option<&'static str> {
+        self.inner.connection_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint() {
+        let mut buf = Vec::new();
+        encode_varint(&mut buf, 1);
+        encode_varint(&mut buf, 300);
+        assert_eq!(buf, [0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn start_frame() {
+        let frame = control_frame(CONTROL_START);
+        assert_eq!(&frame[..4], &[0, 0, 0, 0]);
+        assert_eq!(&frame[4..8], &[0, 0, 0, 34]);
+        assert_eq!(&frame[8..12], &[0, 0, 0, 2]);
+        assert_eq!(&frame[12..16], &[0, 0, 0, 1]);
+        assert_eq!(&frame[16..20], &[0, 0, 0, 22]);
+        assert_eq!(&frame[20..], CONTENT_TYPE);
+    }
+
+    #[test]
+    fn stop_frame() {
+        let frame = control_frame(CONTROL_STOP);
+        assert_eq!(frame, [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]);
+        assert_eq!(read_control_frame(&mut &frame[..]).unwrap(), CONTROL_STOP);
+        assert_eq!(
+            read_control_frame(&mut &control_frame(CONTROL_START)[..]).unwrap(),
+            CONTROL_START
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::config::HealthCheck;
+use crate::metrics;
+use crate::resolver::measured::MeasuredResolver;
+use crate::resolver::Resolver;
+use crate::{STDERR, STDOUT};
+
+use slog::{debug, info, warn};
+use tokio::prelude::*;
+use tokio::timer::Interval;
+use trust_dns::op::Query;
+
+/// The health state of an upstream, acting as a circuit breaker.
+/// The circuit opens after a number of consecutive failures
+/// and closes again once a query (usually a probe) succeeds.
+#[derive(Debug)]
+pub struct Health {
+    name: String,
+    threshold: usize,
+    failures: AtomicUsize,
+    healthy: AtomicBool,
+}
+
+impl Health {
+    pub fn new(name: String, threshold: usize) -> Self {
+        metrics::UPSTREAM_HEALTHY.with_label_values(&[&name]).set(1);
+        Health {
+            name,
+            threshold,
+            failures: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn report_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        if !self.healthy.swap(true, Ordering::Relaxed) {
+            info!(STDOUT, "Upstream {} is healthy again", self.name);
+            metrics::UPSTREAM_HEALTHY
+                .with_label_values(&[&self.name])
+                .set(1);
+        }
+    }
+
+    pub fn report_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.threshold && self.healthy.swap(false, Ordering::Relaxed) {
+            warn!(
+                STDERR,
+                "Upstream {} is unhealthy after {} consecutive failures", self.name, failures
+            );
+            metrics::UPSTREAM_HEALTHY
+                .with_label_values(&[&self.name])
+                .set(0);
+        }
+    }
+}
+
+/// Probes the upstream periodically until `retired` is set. The results are reported
+/// to its health state by the resolver itself.
+pub fn spawn_probes(
+    name: String,
+    resolver: MeasuredResolver,
+    config: &HealthCheck,
+    retired: Arc<AtomicBool>,
+) {
+    let query = Query::query(config.name.clone(), config.record_type);
+    let probes = Interval::new(Instant::now() + config.interval, config.interval)
+        .map_err(|e| warn!(STDERR, "Health check timer error: {}", e))
+        .take_while(move |_| Ok(!retired.load(Ordering::Relaxed)))
+        .for_each(move |_| {
+            let name = name.clone();
+            resolver.query(query.clone()).then(move |res| {
+                if let Err(e) = res {
+                    debug!(STDERR, "Health probe to {} failed: {}", name, e);
+                }
+                Ok(())
+            })
+        });
+    tokio::spawn(probes);
+}
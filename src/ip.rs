@@ -1,6 +1,17 @@
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use std::net::IpAddr;
 
+const PRIVATE_V4: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+];
+
+const PRIVATE_V6: &[&str] = &["::/128", "::1/128", "fc00::/7", "fe80::/10"];
+
 #[derive(Debug)]
 pub struct IpRange {
     v4: iprange::IpRange<Ipv4Net>,
@@ -31,6 +42,24 @@ impl IpRange {
         self.v6.simplify();
     }
 
+    /// Private, loopback and link-local addresses, which public names should not resolve to.
+    /// IPv4-mapped IPv6 forms of the IPv4 ones are included too.
+    pub fn private() -> Self {
+        let mut range = IpRange::new();
+        for net in PRIVATE_V4 {
+            let net: Ipv4Net = net.parse().expect("invalid private network");
+            range.add(IpNet::V4(net));
+            let mapped = Ipv6Net::new(net.network().to_ipv6_mapped(), net.prefix_len() + 96)
+                .expect("invalid mapped network");
+            range.add(IpNet::V6(mapped));
+        }
+        for net in PRIVATE_V6 {
+            range.add(net.parse().expect("invalid private network"));
+        }
+        range.simplify();
+        range
+    }
+
     pub fn contains(&self, addr: IpAddr) -> bool {
         match addr {
             IpAddr::V4(addr) => self.v4.contains(&addr),
@@ -44,3 +73,31 @@ impl Default for IpRange {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_ranges() {
+        let range = IpRange::private();
+        let contains = |addr: &str| range.contains(addr.parse().unwrap());
+        for addr in &[
+            "10.1.2.3",
+            "127.0.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:10.1.2.3",
+            "::ffff:127.0.0.1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(contains(addr), "{} is private", addr);
+        }
+        for addr in &["172.32.0.1", "8.8.8.8", "2001:db8::1", "::ffff:8.8.8.8"] {
+            assert!(!contains(addr), "{} is public", addr);
+        }
+    }
+}
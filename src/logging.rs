@@ -0,0 +1,354 @@
+//! Builds the `STDOUT` and `STDERR` loggers according to the config.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use crate::config::{Log, LogFilter, LogFormat, LogOutput};
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use slog::{o, Drain, Level, Logger, Never, OwnedKVList, Record, KV};
+use slog_term::{CompactFormat, Decorator, FullFormat, PlainDecorator, TermDecorator};
+
+type BoxDrain = Box<Drain<Ok = (), Err = Never> + Send>;
+
+lazy_static! {
+    static ref SETTINGS: Mutex<Option<Settings>> = Mutex::new(None);
+}
+
+struct Settings {
+    log: Log,
+    // Shared by both loggers
+    file: Option<Arc<Mutex<RotatingFile>>>,
+}
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Must be called before the loggers are first used. Otherwise the defaults are used.
+pub fn init(log: &Log) -> io::Result<()> {
+    let file = match &log.output {
+        LogOutput::File {
+            path,
+            max_size,
+            keep,
+        } => Some(Arc::new(Mutex::new(RotatingFile::open(
+            path.clone(),
+            *max_size,
+            *keep,
+        )?))),
+        LogOutput::Syslog => {
+            // Fail early if syslog is not available
+            Syslog::connect()?;
+            None
+        }
+        LogOutput::Journald => {
+            Journald::connect()?;
+            None
+        }
+        LogOutput::Terminal => None,
+    };
+    *SETTINGS.lock() = Some(Settings {
+        log: log.clone(),
+        file,
+    });
+    Ok(())
+}
+
+pub fn stdout_logger() -> Logger {
+    let (drain, filter) = build(Stream::Stdout);
+    let drain = ModuleFilter { drain, filter };
+    let drain = slog_async::Async::new(drain).build().fuse();
+
+    Logger::root(drain, o!())
+}
+
+pub fn stderr_logger() -> Logger {
+    let (drain, filter) = build(Stream::Stderr);
+    let drain = ModuleFilter { drain, filter };
+    let drain = std::sync::Mutex::new(drain).fuse();
+
+    Logger::root(drain, o!())
+}
+
+fn build(stream: Stream) -> (BoxDrain, LogFilter) {
+    let settings = SETTINGS.lock();
+    let default = Log::default();
+    let log = settings.as_ref().map(|s| &s.log).unwrap_or(&default);
+
+    let drain = match &log.output {
+        LogOutput::Terminal => match (log.format, stream) {
+            (LogFormat::Json, Stream::Stdout) => json(io::stdout()),
+            (LogFormat::Json, Stream::Stderr) => json(io::stderr()),
+            (format, Stream::Stdout) => formatted(format, TermDecorator::new().stdout().build()),
+            (format, Stream::Stderr) => formatted(format, TermDecorator::new().stderr().build()),
+        },
+        LogOutput::File { .. } => {
+            let file = settings
+                .as_ref()
+                .and_then(|s| s.file.clone())
+                .expect("log file is not opened");
+            let writer = SharedFile {
+                file,
+                buffer: Vec::new(),
+            };
+            match log.format {
+                LogFormat::Json => json(writer),
+                format => formatted(format, PlainDecorator::new(writer)),
+            }
+        }
+        // Already checked in `init`
+        LogOutput::Syslog => Box::new(Syslog::connect().expect("syslog is not available")),
+        LogOutput::Journald => Box::new(Journald::connect().expect("journald is not available")),
+    };
+    (drain, log.filter.clone())
+}
+
+fn formatted<D: Decorator + Send + 'static>(format: LogFormat, decorator: D) -> BoxDrain {
+    match format {
+        LogFormat::Full => Box::new(FullFormat::new(decorator).build().fuse()),
+        _ => Box::new(CompactFormat::new(decorator).build().fuse()),
+    }
+}
+
+fn json<W: Write + Send + 'static>(writer: W) -> BoxDrain {
+    Box::new(slog_json::Json::default(writer).fuse())
+}
+
+struct ModuleFilter {
+    drain: BoxDrain,
+    filter: LogFilter,
+}
+
+impl Drain for ModuleFilter {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), Never> {
+        if self.filter.enabled(record.level(), record.module()) {
+            self.drain.log(record, values)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A log file which is renamed to `<path>.1` when it grows too large.
+/// Older files are shifted to `<path>.2`, `<path>.3` and so on.
+struct RotatingFile {
+    path: String,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: String, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..self.keep).rev() {
+            // Missing files are fine
+            let _ = fs::rename(
+                format!("{}.{}", self.path, i),
+                format!("{}.{}", self.path, i + 1),
+            );
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_lines(&mut self, lines: &[u8]) -> io::Result<()> {
+        if self.max_size > 0 && self.size > 0 && self.size + lines.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(lines)?;
+        self.size += lines.len() as u64;
+        Ok(())
+    }
+}
+
+/// Buffers a logger's output so that only whole lines are written to the shared file
+struct SharedFile {
+    file: Arc<Mutex<RotatingFile>>,
+    buffer: Vec<u8>,
+}
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if let Some(pos) = self.buffer.iter().rposition(|b| *b == b'\n') {
+            let lines: Vec<u8> = self.buffer.drain(..=pos).collect();
+            self.file.lock().write_lines(&lines)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Formats the message followed by its key-value pairs
+fn message(record: &Record, values: &OwnedKVList) -> String {
+    let mut serializer = KvText(format!("{}", record.msg()));
+    let _ = record.kv().serialize(record, &mut serializer);
+    let _ = values.serialize(record, &mut serializer);
+    serializer.0
+}
+
+struct KvText(String);
+
+impl slog::Serializer for KvText {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        use std::fmt::Write;
+        let _ = write!(self.0, ", {}: {}", key, val);
+        Ok(())
+    }
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Critical => 2,
+        Level::Error => 3,
+        Level::Warning => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+/// Sends messages to the local syslog daemon with the daemon facility
+#[cfg(unix)]
+struct Syslog {
+    socket: UnixDatagram,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    const FACILITY: u8 = 3;
+
+    fn connect() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect("/dev/log")?;
+        Ok(Syslog { socket })
+    }
+}
+
+#[cfg(unix)]
+impl Drain for Syslog {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), Never> {
+        let line = format!(
+            "<{}>yadd[{}]: {}",
+            Self::FACILITY * 8 + severity(record.level()),
+            std::process::id(),
+            message(record, values)
+        );
+        // There is nowhere else to report the failure
+        let _ = self.socket.send(line.as_bytes());
+        Ok(())
+    }
+}
+
+/// Sends messages to systemd-journald using its native protocol
+#[cfg(unix)]
+struct Journald {
+    socket: UnixDatagram,
+}
+
+#[cfg(unix)]
+impl Journald {
+    fn connect() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect("/run/systemd/journal/socket")?;
+        Ok(Journald { socket })
+    }
+
+    fn field(buf: &mut Vec<u8>, name: &str, value: &[u8]) {
+        buf.extend_from_slice(name.as_bytes());
+        if value.contains(&b'\n') {
+            // Values with newlines are length-prefixed
+            buf.push(b'\n');
+            let len = value.len() as u64;
+            buf.extend((0..8).map(|i| (len >> (8 * i)) as u8));
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value);
+        buf.push(b'\n');
+    }
+}
+
+#[cfg(unix)]
+impl Drain for Journald {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), Never> {
+        let mut payload = Vec::new();
+        Self::field(
+            &mut payload,
+            "PRIORITY",
+            severity(record.level()).to_string().as_bytes(),
+        );
+        Self::field(&mut payload, "SYSLOG_IDENTIFIER", b"yadd");
+        Self::field(&mut payload, "CODE_MODULE", record.module().as_bytes());
+        Self::field(&mut payload, "MESSAGE", message(record, values).as_bytes());
+        let _ = self.socket.send(&payload);
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+struct Syslog;
+
+#[cfg(not(unix))]
+impl Syslog {
+    fn connect() -> io::Result<slog::Discard> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "syslog is not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(not(unix))]
+struct Journald;
+
+#[cfg(not(unix))]
+impl Journald {
+    fn connect() -> io::Result<slog::Discard> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "journald is not supported on this platform",
+        ))
+    }
+}
@@ -1,27 +1,32 @@
+use std::env;
 use std::fmt::Display;
-use std::fs::File;
-use std::io::prelude::*;
+use std::io;
 use std::process::exit;
+use std::sync::Arc;
 
-use crate::config::{Config, ConfigBuilder};
-use crate::dispatcher::Dispatcher;
+use crate::admin::Admin;
+use crate::config::Config;
+use crate::dispatcher::{Dispatcher, SharedDispatcher};
+use crate::dnstap::Dnstap;
+use crate::querylog::QueryLog;
 
 use clap::{App, Arg};
 use failure::Error;
 use lazy_static::lazy_static;
-use slog::{crit, debug, info};
-use slog::{o, Drain, Logger};
+use slog::Logger;
+use slog::{crit, debug, error, info};
 use tokio;
 use tokio::net::udp::UdpSocket;
 use tokio::prelude::*;
 
 lazy_static! {
-    static ref STDOUT: Logger = stdout_logger();
-    static ref STDERR: Logger = stderr_logger();
+    static ref STDOUT: Logger = logging::stdout_logger();
+    static ref STDERR: Logger = logging::stderr_logger();
 }
 
 fn main() {
-    let conf = config().unwrap_or_log();
+    let (conf, config_path) = config().unwrap_or_log();
+    logging::init(&conf.log).unwrap_or_log_with("Unable to set up logging");
     debug!(STDERR, "{:#?}", conf);
 
     let bind =
@@ -29,17 +34,90 @@ fn main() {
     info!(STDOUT, "Listening on UDP: {}", conf.bind);
     // // trust_dns_server::logger::debug();
 
+    let metrics_server = conf.metrics.map(|addr| {
+        let server = metrics::bind(&addr)
+            .unwrap_or_log_with(format!("Unable to bind metrics server to {}", addr));
+        info!(STDOUT, "Serving metrics on HTTP: {}", addr);
+        server
+    });
+
+    let admin_listener = conf.admin.as_ref().map(|listener| {
+        let bound = admin::bind(listener)
+            .unwrap_or_log_with(format!("Unable to bind admin server to {:?}", listener));
+        info!(STDOUT, "Serving admin API on {:?}", listener);
+        bound
+    });
+
+    let query_log = conf.query_log.as_ref().map(|output| {
+        Arc::new(QueryLog::new(output).unwrap_or_log_with("Unable to open the query log"))
+    });
+
+    let dnstap = conf.dnstap.as_ref().map(|output| {
+        Arc::new(Dnstap::new(output).unwrap_or_log_with("Unable to open the dnstap output"))
+    });
+
+    // The dnstap stream is ended with a STOP frame when yadd is terminated
+    let stopped_dnstap = dnstap.clone();
+
     let future = future::lazy(move || {
-        let resolver = Dispatcher::new(conf);
-        let server = trust_dns_server::ServerFuture::new(resolver);
+        if let Some(server) = metrics_server {
+            tokio::spawn(metrics::serve(server));
+        }
+        let dispatcher = Dispatcher::new(conf, query_log.clone(), dnstap.clone())
+            .unwrap_or_log_with("Unable to set up upstreams");
+        let dispatcher = SharedDispatcher::new(dispatcher);
+        if let Some(listener) = admin_listener {
+            let admin = Admin::new(dispatcher.clone(), config_path, query_log, dnstap);
+            tokio::spawn(admin::serve(listener, admin));
+        }
+        let server = trust_dns_server::ServerFuture::new(dispatcher);
         server.register_socket(bind);
-        future::empty::<(), ()>()
+        match stopped_dnstap {
+            Some(dnstap) => Box::new(
+                shutdown_signal()
+                    .map_err(|e| error!(STDERR, "Unable to listen for signals: {}", e))
+                    .and_then(move |()| -> Result<(), ()> {
+                        info!(STDOUT, "Shutting down");
+                        dnstap.finish();
+                        exit(0)
+                    }),
+            ) as Box<Future<Item = (), Error = ()> + Send>,
+            None => Box::new(future::empty()),
+        }
     });
 
     tokio::run(future);
 }
 
-fn config() -> Result<Config, Error> {
+/// Resolves on SIGINT or SIGTERM
+#[cfg(unix)]
+fn shutdown_signal() -> impl Future<Item = (), Error = io::Error> {
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+    let signal = |signal| {
+        Signal::new(signal)
+            .flatten_stream()
+            .into_future()
+            .map(|_| ())
+            .map_err(|(e, _)| e)
+    };
+    signal(SIGINT)
+        .select(signal(SIGTERM))
+        .map(|_| ())
+        .map_err(|(e, _)| e)
+}
+
+/// Resolves on Ctrl-C
+#[cfg(not(unix))]
+fn shutdown_signal() -> impl Future<Item = (), Error = io::Error> {
+    tokio_signal::ctrl_c()
+        .flatten_stream()
+        .into_future()
+        .map(|_| ())
+        .map_err(|(e, _)| e)
+}
+
+/// Returns the config and the path it is loaded from
+fn config() -> Result<(Config, String), Error> {
     let matches = App::new("Yet Another DNS Dispatcher")
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -53,32 +131,27 @@ fn config() -> Result<Config, Error> {
                 .default_value("config.toml")
                 .help("Specify the config file"),
         )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .help("Set the log level, optionally per module, e.g. info,yadd::dispatcher=debug"),
+        )
         .get_matches();
     let config_path = matches
         .value_of("config")
         .expect("CONFIG_FILE argument not found");
-    let mut file = File::open(config_path)?;
-    let mut content = String::new();
-    file.read_to_string(&mut content)?;
-
-    let builder: ConfigBuilder = toml::from_str(&content)?;
-    builder.build()
-}
+    let mut config = Config::load(config_path)?;
 
-fn stdout_logger() -> Logger {
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-
-    Logger::root(drain, o!())
-}
-
-fn stderr_logger() -> Logger {
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::CompactFormat::new(decorator).build();
-    let drain = std::sync::Mutex::new(drain).fuse();
-
-    Logger::root(drain, o!())
+    // The environment variable and the command line override the config file
+    if let Ok(directives) = env::var("RUST_LOG") {
+        config.log.filter.apply(&directives)?;
+    }
+    if let Some(directives) = matches.value_of("log-level") {
+        config.log.filter.apply(directives)?;
+    }
+    Ok((config, config_path.to_owned()))
 }
 
 trait ShouldSuccess {
@@ -132,7 +205,13 @@ impl<T, E> Transpose for Option<Result<T, E>> {
     }
 }
 
+mod admin;
 mod config;
 mod dispatcher;
+mod dnstap;
+mod health;
 mod ip;
+mod logging;
+mod metrics;
+mod querylog;
 mod resolver;
@@ -0,0 +1,129 @@
+//! Prometheus metrics served at /metrics.
+//! There is no cache hit ratio, because yadd does not cache responses.
+
+use std::net::SocketAddr;
+
+use crate::STDERR;
+
+use hyper::server::conn::AddrIncoming;
+use hyper::server::Builder;
+use hyper::service::service_fn_ok;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use slog::error;
+use tokio::prelude::*;
+
+lazy_static! {
+    pub static ref QUERIES: IntCounterVec = register_int_counter_vec!(
+        "yadd_queries_total",
+        "Queries received from clients",
+        &["type", "client"]
+    )
+    .unwrap();
+    pub static ref REQUEST_RULE_HITS: IntCounterVec = register_int_counter_vec!(
+        "yadd_request_rule_hits_total",
+        "Queries matching each dispatching rule",
+        &["rule"]
+    )
+    .unwrap();
+    pub static ref RESPONSE_RULE_DROPS: IntCounterVec = register_int_counter_vec!(
+        "yadd_response_rule_drops_total",
+        "Upstream responses dropped by each response rule",
+        &["rule", "upstream"]
+    )
+    .unwrap();
+    pub static ref BOGUS_NXDOMAINS: IntCounterVec = register_int_counter_vec!(
+        "yadd_bogus_nxdomains_total",
+        "Upstream responses turned into NXDOMAIN by bogus-nxdomain",
+        &["upstream"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_LATENCY: HistogramVec = register_histogram_vec!(
+        "yadd_upstream_latency_seconds",
+        "Time taken by upstreams to answer",
+        &["upstream"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+        "yadd_upstream_timeouts_total",
+        "Queries to upstreams which timed out",
+        &["upstream"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "yadd_upstream_errors_total",
+        "Queries to upstreams which failed for reasons other than timeouts",
+        &["upstream"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_WINS: IntCounterVec = register_int_counter_vec!(
+        "yadd_upstream_wins_total",
+        "Responses returned to clients from each upstream",
+        &["upstream"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_HEALTHY: IntGaugeVec = register_int_gauge_vec!(
+        "yadd_upstream_healthy",
+        "Whether the upstream is considered healthy (1) or not (0)",
+        &["upstream"]
+    )
+    .unwrap();
+    pub static ref TCP_RECONNECTS: IntCounterVec = register_int_counter_vec!(
+        "yadd_tcp_reconnects_total",
+        "Connections established to TCP and TLS upstreams",
+        &["server"]
+    )
+    .unwrap();
+    pub static ref TCP_FALLBACKS: IntCounterVec = register_int_counter_vec!(
+        "yadd_tcp_fallbacks_total",
+        "Truncated UDP responses retried over TCP",
+        &["server"]
+    )
+    .unwrap();
+    pub static ref SERVFAILS: IntCounter =
+        register_int_counter!("yadd_servfails_total", "SERVFAIL responses sent to clients")
+            .unwrap();
+    pub static ref COALESCED_QUERIES: IntCounter = register_int_counter!(
+        "yadd_coalesced_queries_total",
+        "Queries answered with the responses to an identical query in flight"
+    )
+    .unwrap();
+}
+
+pub fn bind(addr: &SocketAddr) -> Result<Builder<AddrIncoming>, hyper::Error> {
+    Server::try_bind(addr)
+}
+
+/// Serves the metrics in the Prometheus text format at `/metrics`.
+pub fn serve(server: Builder<AddrIncoming>) -> impl Future<Item = (), Error = ()> {
+    server
+        .serve(|| service_fn_ok(handle))
+        .map_err(|e| error!(STDERR, "Metrics server error: {}", e))
+}
+
+fn handle(req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return resp;
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!(STDERR, "Unable to encode metrics: {}", e);
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return resp;
+    }
+    let mut resp = Response::new(Body::from(buffer));
+    if let Ok(content_type) = encoder.format_type().parse() {
+        resp.headers_mut()
+            .insert(hyper::header::CONTENT_TYPE, content_type);
+    }
+    resp
+}
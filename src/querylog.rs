@@ -0,0 +1,271 @@
+use std::fs::OpenOptions;
+use std::io::{self, LineWriter, Write};
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::QueryLogOutput;
+use crate::resolver::dnssec::Status;
+use crate::STDERR;
+
+use chrono::Utc;
+use failure::Error;
+use serde_derive::Serialize;
+use slog::{error, warn};
+use trust_dns::op::Query;
+use trust_dns_proto::op::response_code::ResponseCode;
+
+/// Entries waiting to be written. New entries are discarded if the writer can't keep up.
+const QUEUE_SIZE: usize = 4096;
+
+/// One line of the query log, describing how a request is handled
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub timestamp: String,
+    pub client: IpAddr,
+    pub qname: String,
+    pub qtype: String,
+    /// Index of the matched dispatching rule. `None` means the defaults are used.
+    pub rule: Option<usize>,
+    /// Upstreams the query is sent to, in order
+    pub upstreams: Vec<String>,
+    pub outcomes: Vec<Outcome>,
+    /// Whether the query waits for an identical query in flight instead of being sent.
+    /// The upstreams and outcomes are only logged for the query in flight.
+    pub coalesced: bool,
+    /// The upstream whose response is returned to the client
+    pub selected: Option<String>,
+    pub rcode: String,
+    pub latency_ms: f64,
+}
+
+impl Entry {
+    pub fn new(client: IpAddr, query: &Query) -> Self {
+        Entry {
+            timestamp: Utc::now().to_rfc3339(),
+            client,
+            qname: query.name().to_ascii(),
+            qtype: query.query_type().to_string(),
+            rule: None,
+            upstreams: Vec::new(),
+            outcomes: Vec::new(),
+            coalesced: false,
+            selected: None,
+            rcode: String::new(),
+            latency_ms: 0.0,
+        }
+    }
+
+    pub fn finish(&mut self, rcode: ResponseCode, latency: Duration) {
+        self.rcode = rcode.to_string();
+        self.latency_ms = as_millis(latency);
+    }
+}
+
+/// What happened to the query sent to an upstream
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub upstream: String,
+    pub latency_ms: f64,
+    pub result: OutcomeResult,
+    pub answers: Vec<IpAddr>,
+    /// Index of the response rule which accepts or drops the response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<usize>,
+    /// The check deciding before the response rules:
+    /// "empty", "bogus-nxdomain" or "rebind"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check: Option<&'static str>,
+    /// The result of DNSSEC validation, if enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dnssec: Option<Status>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Outcome {
+    pub fn new(upstream: String, latency: Duration, result: OutcomeResult) -> Self {
+        Outcome {
+            upstream,
+            latency_ms: as_millis(latency),
+            result,
+            answers: Vec::new(),
+            rule: None,
+            check: None,
+            dnssec: None,
+            error: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+pub enum OutcomeResult {
+    #[serde(rename = "accept")]
+    Accept,
+    #[serde(rename = "drop")]
+    Drop,
+    #[serde(rename = "error")]
+    Error,
+}
+
+pub fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1e3 + f64::from(duration.subsec_nanos()) / 1e6
+}
+
+/// Writes entries as JSON lines in a background thread
+pub struct QueryLog {
+    sender: SyncSender<String>,
+}
+
+impl QueryLog {
+    pub fn new(output: &QueryLogOutput) -> Result<Self, Error> {
+        let writer: Box<Write + Send> = match output {
+            QueryLogOutput::Stdout => Box::new(io::stdout()),
+            QueryLogOutput::File(path) => Box::new(LineWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            QueryLogOutput::Syslog => Box::new(Syslog::connect()?),
+        };
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("query-log".to_owned())
+            .spawn(move || Self::write_all(writer, receiver))?;
+        Ok(QueryLog { sender })
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!(STDERR, "Unable to serialize query log: {}", e);
+                return;
+            }
+        };
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(line) {
+            warn!(STDERR, "Query log queue is full. Entry discarded.");
+        }
+    }
+
+    fn write_all(mut writer: Box<Write + Send>, receiver: Receiver<String>) {
+        for line in receiver {
+            if let Err(e) = writeln!(writer, "{}", line) {
+                error!(STDERR, "Unable to write query log: {}", e);
+            }
+        }
+    }
+}
+
+/// Sends every line as a message to the local syslog daemon
+#[cfg(unix)]
+struct Syslog {
+    socket: std::os::unix::net::UnixDatagram,
+    buffer: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    // Facility local0, severity informational
+    const PRIORITY: u8 = 16 * 8 + 6;
+
+    fn connect() -> io::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect("/dev/log")?;
+        Ok(Syslog {
+            socket,
+            buffer: Vec::new(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Write for Syslog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let mut message = format!("<{}>yadd: ", Self::PRIORITY).into_bytes();
+            message.extend_from_slice(&line[..line.len() - 1]);
+            self.socket.send(&message)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+struct Syslog;
+
+#[cfg(not(unix))]
+impl Syslog {
+    fn connect() -> io::Result<io::Sink> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "syslog is not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns_proto::rr::record_type::RecordType;
+    use trust_dns_proto::rr::Name;
+
+    #[test]
+    fn entry_shape() {
+        let query = Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::A);
+        let mut entry = Entry::new("192.0.2.1".parse().unwrap(), &query);
+        entry.rule = Some(1);
+        entry.upstreams = vec!["dnspod".to_owned(), "opendns".to_owned()];
+        let mut dropped = Outcome::new(
+            "dnspod".to_owned(),
+            Duration::from_millis(12),
+            OutcomeResult::Drop,
+        );
+        dropped.answers = vec!["203.0.113.1".parse().unwrap()];
+        dropped.rule = Some(0);
+        let mut failed = Outcome::new(
+            "opendns".to_owned(),
+            Duration::from_millis(5000),
+            OutcomeResult::Error,
+        );
+        failed.error = Some("timed out".to_owned());
+        entry.outcomes = vec![dropped, failed];
+        entry.finish(ResponseCode::ServFail, Duration::from_micros(5001500));
+
+        let json: serde_json::Value = serde_json::to_value(&entry).unwrap();
+        assert!(json["timestamp"].is_string());
+        assert_eq!(json["client"], "192.0.2.1");
+        assert_eq!(json["qname"], "example.com.");
+        assert_eq!(json["qtype"], "A");
+        assert_eq!(json["rule"], 1);
+        assert_eq!(json["upstreams"], serde_json::json!(["dnspod", "opendns"]));
+        assert_eq!(
+            json["outcomes"],
+            serde_json::json!([
+                {
+                    "upstream": "dnspod",
+                    "latency_ms": 12.0,
+                    "result": "drop",
+                    "answers": ["203.0.113.1"],
+                    "rule": 0
+                },
+                {
+                    "upstream": "opendns",
+                    "latency_ms": 5000.0,
+                    "result": "error",
+                    "answers": [],
+                    "error": "timed out"
+                }
+            ])
+        );
+        assert!(json["selected"].is_null());
+        assert_eq!(json["rcode"], ResponseCode::ServFail.to_string());
+        assert_eq!(json["latency_ms"], 5001.5);
+    }
+}
@@ -0,0 +1,66 @@
+//! Resolves the hostnames of upstreams through other upstreams.
+
+use super::*;
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::resolver::connect::ResolvedAddrs;
+use crate::{STDERR, STDOUT};
+
+use slog::{debug, info, warn};
+use tokio::timer::Interval;
+use trust_dns::rr::{Name, RecordType};
+
+/// Resolves A and AAAA records of `host` at once and then every `interval`
+/// until `retired` is set. The addresses are kept if the resolution fails.
+pub fn spawn_resolution<R>(
+    host: Name,
+    bootstrap: R,
+    addrs: ResolvedAddrs,
+    interval: Duration,
+    retired: Arc<AtomicBool>,
+) where
+    R: Resolver + 'static,
+{
+    let resolutions = Interval::new(Instant::now(), interval)
+        .map_err(|e| warn!(STDERR, "Bootstrap timer error: {}", e))
+        .take_while(move |_| Ok(!retired.load(Ordering::Relaxed)))
+        .for_each(move |_| {
+            let lookup = |record_type| {
+                bootstrap
+                    .query(Query::query(host.clone(), record_type))
+                    .then(|res| -> Result<Vec<IpAddr>, ()> {
+                        Ok(res
+                            .map(|resp| {
+                                resp.answers()
+                                    .iter()
+                                    .filter_map(|record| record.rdata().to_ip_addr())
+                                    .collect()
+                            })
+                            .unwrap_or_default())
+                    })
+            };
+            let host = host.clone();
+            let addrs = addrs.clone();
+            lookup(RecordType::A)
+                .join(lookup(RecordType::AAAA))
+                .map(move |(mut v4, v6)| {
+                    v4.extend(v6);
+                    if v4.is_empty() {
+                        warn!(STDERR, "Unable to resolve {}", host);
+                        return;
+                    }
+                    let mut addrs = addrs.write();
+                    if *addrs != v4 {
+                        info!(STDOUT, "{} is resolved to {:?}", host, v4);
+                        *addrs = v4;
+                    } else {
+                        debug!(STDERR, "{} is still resolved to {:?}", host, v4);
+                    }
+                })
+        });
+    tokio::spawn(resolutions);
+}
@@ -0,0 +1,232 @@
+//! Opens the TCP connections to upstreams, which may be given by hostnames.
+//! All the resolved addresses are tried in the Happy Eyeballs way (RFC 8305),
+//! unless the connections go through a proxy.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::resolver::proxy::{Proxy, Target};
+use crate::resolver::socket::SocketOptions;
+
+use parking_lot::RwLock;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::timer::{Delay, Timeout};
+
+/// Milliseconds to wait before trying the next address if the last attempt is still pending
+const CONNECTION_ATTEMPT_DELAY_MS: u64 = 250;
+
+/// The addresses of a hostname, replaced every time it is resolved again
+pub type ResolvedAddrs = Arc<RwLock<Vec<IpAddr>>>;
+
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    Addr(SocketAddr),
+    Name {
+        host: String,
+        port: u16,
+        addrs: ResolvedAddrs,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Connector {
+    endpoint: Endpoint,
+    proxy: Option<Proxy>,
+    socket: SocketOptions,
+}
+
+impl Connector {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Connector {
+            endpoint,
+            proxy: None,
+            socket: SocketOptions::default(),
+        }
+    }
+
+    /// Hostnames are resolved by the proxy, if any
+    pub fn with_proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Applied to the connections to the proxy instead if there is one
+    pub fn with_socket_options(mut self, socket: SocketOptions) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    /// The address shown in logs and metrics
+    pub fn name(&self) -> String {
+        match &self.endpoint {
+            Endpoint::Addr(addr) => addr.to_string(),
+            Endpoint::Name { host, port, .. } => format!("{}:{}", host, port),
+        }
+    }
+
+    /// Labels the messages sent over the connection, which is not always the real peer address
+    pub fn addr_hint(&self) -> SocketAddr {
+        match &self.endpoint {
+            Endpoint::Addr(addr) => *addr,
+            Endpoint::Name { port, .. } => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *port),
+        }
+    }
+
+    pub fn connect(
+        &self,
+        timeout: Duration,
+    ) -> Box<Future<Item = TcpStream, Error = io::Error> + Send> {
+        if let Some(proxy) = &self.proxy {
+            let proxy = proxy.clone();
+            let target = match &self.endpoint {
+                Endpoint::Addr(addr) => Target::Addr(*addr),
+                Endpoint::Name { host, port, .. } => Target::Name(host.clone(), *port),
+            };
+            let connect = self
+                .socket
+                .connect(&proxy.addr)
+                .and_then(move |stream| proxy.handshake(stream, target));
+            return Box::new(with_timeout(connect, timeout));
+        }
+
+        let addrs = match &self.endpoint {
+            Endpoint::Addr(addr) => vec![*addr],
+            Endpoint::Name { host, port, addrs } => {
+                let addrs = addrs.read();
+                if addrs.is_empty() {
+                    return Box::new(future::err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} is not resolved yet", host),
+                    )));
+                }
+                interleave(&addrs)
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, *port))
+                    .filter(|addr| self.socket.accepts(addr))
+                    .collect()
+            }
+        };
+        Box::new(with_timeout(
+            HappyEyeballs::new(addrs, self.socket.clone()),
+            timeout,
+        ))
+    }
+}
+
+/// Fails with `TimedOut` if the future does not complete in time
+pub fn with_timeout<F>(
+    future: F,
+    timeout: Duration,
+) -> impl Future<Item = F::Item, Error = io::Error>
+where
+    F: Future<Error = io::Error>,
+{
+    Timeout::new(future, timeout).map_err(|e| {
+        if e.is_elapsed() {
+            io::Error::new(io::ErrorKind::TimedOut, "connection timed out")
+        } else {
+            e.into_inner()
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "timer error"))
+        }
+    })
+}
+
+/// Alternates between IPv6 and IPv4 addresses, starting with IPv6
+fn interleave(ips: &[IpAddr]) -> Vec<IpAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = ips.iter().cloned().partition(|ip| ip.is_ipv6());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut result = Vec::with_capacity(ips.len());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return result,
+            (a, b) => {
+                result.extend(a);
+                result.extend(b);
+            }
+        }
+    }
+}
+
+/// Starts connecting to the next address when the previous attempt fails or takes too long.
+/// The first established connection wins.
+struct HappyEyeballs {
+    pending: std::vec::IntoIter<SocketAddr>,
+    socket: SocketOptions,
+    attempts: Vec<Box<Future<Item = TcpStream, Error = io::Error> + Send>>,
+    delay: Delay,
+    error: Option<io::Error>,
+}
+
+impl HappyEyeballs {
+    fn new(addrs: Vec<SocketAddr>, socket: SocketOptions) -> Self {
+        HappyEyeballs {
+            pending: addrs.into_iter(),
+            socket,
+            attempts: Vec::new(),
+            delay: Delay::new(Instant::now()),
+            error: None,
+        }
+    }
+}
+
+impl Future for HappyEyeballs {
+    type Item = TcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TcpStream, io::Error> {
+        loop {
+            let mut failed = false;
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match self.attempts[i].poll() {
+                    Ok(Async::Ready(stream)) => return Ok(Async::Ready(stream)),
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        self.attempts.swap_remove(i);
+                        self.error = Some(e);
+                        failed = true;
+                    }
+                }
+            }
+
+            let delayed = match self.delay.poll() {
+                Ok(Async::NotReady) => false,
+                _ => true,
+            };
+            if failed || delayed || self.attempts.is_empty() {
+                if let Some(addr) = self.pending.next() {
+                    self.attempts.push(self.socket.connect(&addr));
+                    self.delay
+                        .reset(Instant::now() + Duration::from_millis(CONNECTION_ATTEMPT_DELAY_MS));
+                    continue;
+                }
+            }
+
+            if self.attempts.is_empty() {
+                return Err(self.error.take().unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no address to connect to")
+                }));
+            }
+            return Ok(Async::NotReady);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleave_families() {
+        let ips: Vec<IpAddr> = vec![
+            "1.1.1.1".parse().unwrap(),
+            "1.0.0.1".parse().unwrap(),
+            "8.8.8.8".parse().unwrap(),
+            "2606:4700:4700::1111".parse().unwrap(),
+        ];
+        assert_eq!(interleave(&ips), vec![ips[3], ips[0], ips[1], ips[2]]);
+    }
+}
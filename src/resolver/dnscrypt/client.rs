@@ -0,0 +1,658 @@
+//! The certificates of the resolver are fetched at startup and then every hour.
+//! Queries are sent over UDP and retried over TCP if the response is truncated.
+
+use crate::resolver::*;
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::{self, FromStr};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::metrics;
+use crate::resolver::connect::{Connector, Endpoint};
+use crate::resolver::socket::SocketOptions;
+use crate::{STDERR, STDOUT};
+
+use failure::{err_msg, Error};
+use parking_lot::RwLock;
+use slog::{debug, info, warn};
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::sign;
+use sodiumoxide::randombytes::randombytes_into;
+use tokio::io::{read_exact, write_all};
+use tokio::net::UdpSocket;
+use tokio::reactor::Handle;
+use tokio::timer::{Interval, Timeout};
+use trust_dns::rr::{Name, RData, RecordType};
+use trust_dns_proto::error::ProtoErrorKind;
+
+/// How often the certificates are fetched again, in seconds
+const CERT_REFRESH_INTERVAL_SECS: u64 = 3600;
+
+const CERT_MAGIC: &[u8] = b"DNSC";
+
+/// X25519-XSalsa20Poly1305, the only construction supported
+const ES_VERSION: [u8; 2] = [0, 1];
+
+/// The length of a certificate with X25519-XSalsa20Poly1305
+const CERT_LEN: usize = 124;
+
+const RESOLVER_MAGIC: &[u8] = &[0x72, 0x36, 0x66, 0x6e, 0x76, 0x57, 0x6a, 0x38];
+
+/// The client and the resolver each choose one half of the nonce
+const HALF_NONCE_LEN: usize = box_::NONCEBYTES / 2;
+
+/// Queries over UDP are padded to at least this length
+const MIN_UDP_QUERY_LEN: usize = 256;
+
+/// The longest response accepted over UDP
+const MAX_UDP_RESPONSE_LEN: usize = 4096;
+
+type ProtoFuture<T> = Box<Future<Item = T, Error = ProtoError> + Send>;
+
+/// The parts of a DNSCrypt stamp (https://dnscrypt.info/stamps-specifications) used here
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stamp {
+    pub addr: SocketAddr,
+    /// Signs the certificates
+    pub provider_key: sign::PublicKey,
+    /// Queried for TXT records to get the certificates
+    pub provider_name: Name,
+}
+
+impl fmt::Display for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", self.provider_name, self.addr)
+    }
+}
+
+/// Parses `sdns://` followed by the stamp in URL-safe base64 without padding
+impl FromStr for Stamp {
+    type Err = Error;
+
+    fn from_str(stamp: &str) -> Result<Self, Error> {
+        let invalid = |reason: &str| err_msg(format!("Invalid stamp {}: {}", stamp, reason));
+        if !stamp.starts_with("sdns://") {
+            return Err(invalid("it must start with sdns://"));
+        }
+        let bytes = base64::decode_config(&stamp["sdns://".len()..], base64::URL_SAFE_NO_PAD)
+            .map_err(|_| invalid("bad base64"))?;
+        if bytes.first() != Some(&0x01) {
+            return Err(invalid("not a DNSCrypt stamp"));
+        }
+        // Skips the protocol and the properties, which are for clients choosing resolvers
+        let mut rest = bytes.get(9..).unwrap_or_default();
+        let addr = length_prefixed(&mut rest)
+            .and_then(|addr| str::from_utf8(addr).ok())
+            .and_then(|addr| match addr.parse::<SocketAddr>() {
+                Ok(addr) => Some(addr),
+                Err(_) => addr
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<IpAddr>()
+                    .ok()
+                    .map(|ip| SocketAddr::new(ip, 443)),
+            })
+            .ok_or_else(|| invalid("bad address"))?;
+        let provider_key = length_prefixed(&mut rest)
+            .and_then(sign::PublicKey::from_slice)
+            .ok_or_else(|| invalid("bad provider public key"))?;
+        let provider_name = length_prefixed(&mut rest)
+            .and_then(|name| str::from_utf8(name).ok())
+            .and_then(|name| Name::from_str(&format!("{}.", name.trim_end_matches('.'))).ok())
+            .ok_or_else(|| invalid("bad provider name"))?;
+        Ok(Stamp {
+            addr,
+            provider_key,
+            provider_name,
+        })
+    }
+}
+
+/// Takes a value with a one byte length from the front of `data`
+fn length_prefixed<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let (&len, rest) = data.split_first()?;
+    if rest.len() < len as usize {
+        return None;
+    }
+    let (value, rest) = rest.split_at(len as usize);
+    *data = rest;
+    Some(value)
+}
+
+#[derive(Debug)]
+struct Certificate {
+    resolver_key: box_::PublicKey,
+    /// Starts the queries encrypted with this certificate
+    client_magic: [u8; 8],
+    serial: u32,
+    valid_from: u32,
+    valid_until: u32,
+}
+
+impl Certificate {
+    /// Returns `None` if the certificate is malformed, not signed by the provider
+    /// or for another construction
+    fn parse(data: &[u8], provider_key: &sign::PublicKey) -> Option<Self> {
+        if data.len() != CERT_LEN || &data[..4] != CERT_MAGIC || data[4..6] != ES_VERSION {
+            return None;
+        }
+        let signature = sign::Signature::from_slice(&data[8..72])?;
+        if !sign::verify_detached(&signature, &data[72..], provider_key) {
+            return None;
+        }
+        let mut client_magic = [0u8; 8];
+        client_magic.copy_from_slice(&data[104..112]);
+        Some(Certificate {
+            resolver_key: box_::PublicKey::from_slice(&data[72..104])?,
+            client_magic,
+            serial: be_u32(&data[112..116]),
+            valid_from: be_u32(&data[116..120]),
+            valid_until: be_u32(&data[120..124]),
+        })
+    }
+
+    fn is_valid(&self, now: u32) -> bool {
+        self.valid_from <= now && now <= self.valid_until
+    }
+}
+
+/// A certificate in use and the key shared with the resolver
+struct Session {
+    cert: Certificate,
+    shared_key: box_::PrecomputedKey,
+}
+
+impl Session {
+    /// Returns the packet and the half of the nonce chosen by the client
+    fn encrypt(
+        &self,
+        public_key: &box_::PublicKey,
+        query: &[u8],
+        min_len: usize,
+    ) -> (Vec<u8>, [u8; HALF_NONCE_LEN]) {
+        let mut client_nonce = [0u8; HALF_NONCE_LEN];
+        randombytes_into(&mut client_nonce);
+        let mut nonce = [0u8; box_::NONCEBYTES];
+        nonce[..HALF_NONCE_LEN].copy_from_slice(&client_nonce);
+        let sealed =
+            box_::seal_precomputed(&pad(query, min_len), &box_::Nonce(nonce), &self.shared_key);
+
+        let mut packet =
+            Vec::with_capacity(8 + box_::PUBLICKEYBYTES + HALF_NONCE_LEN + sealed.len());
+        packet.extend(&self.cert.client_magic);
+        packet.extend(&public_key.0[..]);
+        packet.extend(&client_nonce);
+        packet.extend(sealed);
+        (packet, client_nonce)
+    }
+
+    fn decrypt(&self, response: &[u8], client_nonce: &[u8]) -> Result<Vec<u8>, ProtoError> {
+        let header_len = RESOLVER_MAGIC.len() + box_::NONCEBYTES;
+        if response.len() < header_len + box_::MACBYTES
+            || &response[..RESOLVER_MAGIC.len()] != RESOLVER_MAGIC
+            || &response[RESOLVER_MAGIC.len()..RESOLVER_MAGIC.len() + HALF_NONCE_LEN]
+                != client_nonce
+        {
+            return Err("Invalid DNSCrypt response".into());
+        }
+        let nonce = box_::Nonce::from_slice(&response[RESOLVER_MAGIC.len()..header_len])
+            .expect("The nonce has a fixed length");
+        let padded = box_::open_precomputed(&response[header_len..], &nonce, &self.shared_key)
+            .map_err(|()| ProtoError::from("Unable to decrypt the DNSCrypt response"))?;
+        unpad(&padded)
+            .map(|plain| plain.to_vec())
+            .ok_or_else(|| "Invalid padding in the DNSCrypt response".into())
+    }
+}
+
+/// Pads with 0x80 and then zeros to a multiple of 64 bytes, and at least `min_len` bytes
+fn pad(data: &[u8], min_len: usize) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    let len = (padded.len().max(min_len) + 63) / 64 * 64;
+    padded.resize(len, 0);
+    padded
+}
+
+fn unpad(data: &[u8]) -> Option<&[u8]> {
+    let end = data.iter().rposition(|&b| b != 0)?;
+    if data[end] == 0x80 {
+        Some(&data[..end])
+    } else {
+        None
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |n, &b| n << 8 | u32::from(b))
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+fn timeout_error(e: tokio::timer::timeout::Error<io::Error>) -> ProtoError {
+    if e.is_elapsed() {
+        ProtoErrorKind::Timeout.into()
+    } else {
+        e.into_inner()
+            .map(ProtoError::from)
+            .unwrap_or_else(|| "Timer error".into())
+    }
+}
+
+#[derive(Clone)]
+pub struct DnscryptResolver {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    stamp: Stamp,
+    timeout: Duration,
+    socket: SocketOptions,
+    /// Used by the fallback over TCP
+    connector: Connector,
+    /// The key pair of the client, the same for all the queries
+    public_key: box_::PublicKey,
+    secret_key: box_::SecretKey,
+    session: RwLock<Option<Arc<Session>>>,
+}
+
+impl Inner {
+    /// Picks the valid certificate with the highest serial from the TXT records of the provider
+    fn update_session(&self, response: &[u8], id: u16) -> Result<Arc<Session>, ProtoError> {
+        let message = Message::from_vec(response)?;
+        if message.id() != id {
+            return Err("Response ID mismatch".into());
+        }
+        let now = now();
+        let cert = message
+            .answers()
+            .iter()
+            .filter_map(|record| match record.rdata() {
+                RData::TXT(txt) => {
+                    Certificate::parse(&txt.txt_data().concat(), &self.stamp.provider_key)
+                }
+                _ => None,
+            })
+            .filter(|cert| cert.is_valid(now))
+            .max_by_key(|cert| cert.serial)
+            .ok_or_else(|| ProtoError::from(format!("No valid certificate from {}", self.stamp)))?;
+
+        let mut current = self.session.write();
+        if current.as_ref().map(|session| session.cert.serial) != Some(cert.serial) {
+            info!(
+                STDOUT,
+                "Using DNSCrypt certificate {} of {}, valid until {}",
+                cert.serial,
+                self.stamp,
+                cert.valid_until
+            );
+        }
+        let session = Arc::new(Session {
+            shared_key: box_::precompute(&cert.resolver_key, &self.secret_key),
+            cert,
+        });
+        *current = Some(session.clone());
+        Ok(session)
+    }
+}
+
+impl DnscryptResolver {
+    /// Must be called inside a tokio runtime, which the certificates are fetched on
+    pub fn with_options(
+        stamp: Stamp,
+        timeout: Duration,
+        socket: SocketOptions,
+    ) -> Result<Self, Error> {
+        sodiumoxide::init().map_err(|()| err_msg("Unable to initialize libsodium"))?;
+        let (public_key, secret_key) = box_::gen_keypair();
+        let connector =
+            Connector::new(Endpoint::Addr(stamp.addr)).with_socket_options(socket.clone());
+        debug!(
+            STDERR,
+            "DnscryptResolver initialized. DNS requests are forwarded to {}.", stamp
+        );
+        let resolver = DnscryptResolver {
+            inner: Arc::new(Inner {
+                stamp,
+                timeout,
+                socket,
+                connector,
+                public_key,
+                secret_key,
+                session: RwLock::new(None),
+            }),
+        };
+        resolver.spawn_refresh();
+        Ok(resolver)
+    }
+
+    /// Fetches the certificates at once and then every hour until the resolver is dropped
+    fn spawn_refresh(&self) {
+        let inner = Arc::downgrade(&self.inner);
+        let refresh = Interval::new(
+            Instant::now(),
+            Duration::from_secs(CERT_REFRESH_INTERVAL_SECS),
+        )
+        .map_err(|e| warn!(STDERR, "DNSCrypt timer error: {}", e))
+        .for_each(move |_| {
+            let resolver = match Weak::upgrade(&inner) {
+                Some(inner) => DnscryptResolver { inner },
+                // Stops the refresh
+                None => return future::Either::A(future::err(())),
+            };
+            let stamp = resolver.inner.stamp.clone();
+            future::Either::B(resolver.fetch_certificate().then(move |res| {
+                if let Err(e) = res {
+                    warn!(
+                        STDERR,
+                        "Unable to fetch the DNSCrypt certificates of {}: {}", stamp, e
+                    );
+                }
+                Ok(())
+            }))
+        });
+        tokio::spawn(refresh);
+    }
+
+    /// The current session, or a new one if the certificate has expired
+    fn session(&self) -> ProtoFuture<Arc<Session>> {
+        let current = self.inner.session.read().clone();
+        match current {
+            Some(session) if session.cert.is_valid(now()) => Box::new(future::ok(session)),
+            _ => self.fetch_certificate(),
+        }
+    }
+
+    /// Queries the provider name for the certificates
+    fn fetch_certificate(&self) -> ProtoFuture<Arc<Session>> {
+        let mut message = message(
+            &Query::query(self.inner.stamp.provider_name.clone(), RecordType::TXT),
+            &QueryOptions::default(),
+            Vec::new(),
+        );
+        let id = rand::random();
+        message.set_id(id);
+        let bytes = match message.to_vec() {
+            Ok(bytes) => bytes,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let inner = self.inner.clone();
+        Box::new(
+            self.udp_exchange(bytes)
+                .and_then(move |response| inner.update_session(&response, id)),
+        )
+    }
+
+    /// Encrypts `query` with the session and decrypts the response
+    fn exchange(&self, session: Arc<Session>, query: &[u8], tcp: bool) -> ProtoFuture<Message> {
+        let min_len = if tcp { 0 } else { MIN_UDP_QUERY_LEN };
+        let (packet, client_nonce) = session.encrypt(&self.inner.public_key, query, min_len);
+        let response = if tcp {
+            self.tcp_exchange(packet)
+        } else {
+            self.udp_exchange(packet)
+        };
+        Box::new(response.and_then(move |response| {
+            Message::from_vec(&session.decrypt(&response, &client_nonce)?)
+        }))
+    }
+
+    /// Sends `packet` on a new socket and waits for a datagram from the resolver
+    fn udp_exchange(&self, packet: Vec<u8>) -> ProtoFuture<Vec<u8>> {
+        let addr = self.inner.stamp.addr;
+        let socket = match self
+            .inner
+            .socket
+            .bind_udp(&addr)
+            .and_then(|socket| UdpSocket::from_std(socket, &Handle::default()))
+        {
+            Ok(socket) => socket,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        let exchange = socket
+            .send_dgram(packet, &addr)
+            .and_then(move |(socket, _)| {
+                future::loop_fn(socket, move |socket| {
+                    socket.recv_dgram(vec![0u8; MAX_UDP_RESPONSE_LEN]).map(
+                        move |(socket, mut buf, len, from)| {
+                            if from != addr {
+                                warn!(
+                                    STDERR,
+                                    "Dropped a UDP message from {} instead of {}", from, addr
+                                );
+                                return future::Loop::Continue(socket);
+                            }
+                            buf.truncate(len);
+                            future::Loop::Break(buf)
+                        },
+                    )
+                })
+            });
+        Box::new(Timeout::new(exchange, self.inner.timeout).map_err(timeout_error))
+    }
+
+    /// Sends `packet` with a two byte length on a new connection
+    fn tcp_exchange(&self, packet: Vec<u8>) -> ProtoFuture<Vec<u8>> {
+        let mut framed = Vec::with_capacity(packet.len() + 2);
+        framed.push((packet.len() >> 8) as u8);
+        framed.push(packet.len() as u8);
+        framed.extend(packet);
+        let exchange = self
+            .inner
+            .connector
+            .connect(self.inner.timeout)
+            .and_then(move |stream| write_all(stream, framed))
+            .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+            .and_then(|(stream, len)| {
+                read_exact(
+                    stream,
+                    vec![0u8; usize::from(len[0]) << 8 | usize::from(len[1])],
+                )
+            })
+            .map(|(_, buf)| buf);
+        Box::new(Timeout::new(exchange, self.inner.timeout).map_err(timeout_error))
+    }
+}
+
+impl Resolver for DnscryptResolver {
+    fn query_with(
+        &self,
+        query: Query,
+        options: QueryOptions,
+    ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
+        let mut message = message(&query, &options, Vec::new());
+        let id = rand::random();
+        message.set_id(id);
+        let bytes = match message.to_vec() {
+            Ok(bytes) => bytes,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let resolver = self.clone();
+        Box::new(
+            self.session()
+                .and_then(move |session| {
+                    let response = resolver.exchange(session.clone(), &bytes, false);
+                    response.and_then(move |resp| -> ProtoFuture<Message> {
+                        if !resp.truncated() {
+                            return Box::new(future::ok(resp));
+                        }
+                        let addr = resolver.inner.stamp.addr;
+                        debug!(
+                            STDERR,
+                            "Response from {} is truncated. Retry over TCP.", addr
+                        );
+                        metrics::TCP_FALLBACKS
+                            .with_label_values(&[&addr.to_string()])
+                            .inc();
+                        resolver.exchange(session, &bytes, true)
+                    })
+                })
+                .and_then(move |resp| -> Result<DnsResponse, ProtoError> {
+                    if resp.id() != id {
+                        return Err("Response ID mismatch".into());
+                    }
+                    Ok(DnsResponse::from(resp))
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, UdpSocket as StdUdpSocket};
+    use std::thread;
+    use tokio::runtime::Runtime;
+    use trust_dns::rr::rdata::TXT;
+    use trust_dns::rr::Record;
+    use trust_dns_proto::op::MessageType;
+
+    fn stamp_string(addr: &str, provider_key: &sign::PublicKey, provider_name: &str) -> String {
+        let mut bytes = vec![0x01, 1, 0, 0, 0, 0, 0, 0, 0];
+        for value in &[
+            addr.as_bytes(),
+            &provider_key.0[..],
+            provider_name.as_bytes(),
+        ] {
+            bytes.push(value.len() as u8);
+            bytes.extend(*value);
+        }
+        format!(
+            "sdns://{}",
+            base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    #[test]
+    fn parse_stamp() {
+        sodiumoxide::init().unwrap();
+        let (provider_key, _) = sign::gen_keypair();
+        let stamp: Stamp = stamp_string("203.0.113.1", &provider_key, "2.dnscrypt-cert.example")
+            .parse()
+            .unwrap();
+        assert_eq!(stamp.addr, "203.0.113.1:443".parse().unwrap());
+        assert_eq!(stamp.provider_key, provider_key);
+        assert_eq!(
+            stamp.provider_name,
+            Name::from_str("2.dnscrypt-cert.example.").unwrap()
+        );
+
+        let stamp: Stamp = stamp_string("[::1]:5443", &provider_key, "2.dnscrypt-cert.example")
+            .parse()
+            .unwrap();
+        assert_eq!(stamp.addr, "[::1]:5443".parse().unwrap());
+
+        assert!("sdns://AQ".parse::<Stamp>().is_err());
+        assert!("https://example.com".parse::<Stamp>().is_err());
+    }
+
+    #[test]
+    fn padding() {
+        let padded = pad(b"query", MIN_UDP_QUERY_LEN);
+        assert_eq!(padded.len(), MIN_UDP_QUERY_LEN);
+        assert_eq!(unpad(&padded), Some(&b"query"[..]));
+        assert_eq!(pad(&[0u8; 63], 0).len(), 64);
+        assert_eq!(pad(&[0u8; 64], 0).len(), 128);
+        assert_eq!(unpad(&[1, 0, 0]), None);
+    }
+
+    /// A DNSCrypt server answering every A query with 192.0.2.1
+    fn spawn_server(provider_name: &str) -> (SocketAddr, sign::PublicKey) {
+        let (provider_key, provider_secret) = sign::gen_keypair();
+        let (resolver_key, resolver_secret) = box_::gen_keypair();
+        let client_magic = [0x51u8; 8];
+
+        let mut signed = resolver_key.0.to_vec();
+        signed.extend(&client_magic);
+        let now = now();
+        for n in &[1, now - 60, now + 3600] {
+            signed.extend(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, *n as u8]);
+        }
+        let mut cert = CERT_MAGIC.to_vec();
+        cert.extend(&ES_VERSION);
+        cert.extend(&[0, 0]);
+        cert.extend(&sign::sign_detached(&signed, &provider_secret).0[..]);
+        cert.extend(signed);
+
+        let provider_name = Name::from_str(provider_name).unwrap();
+        let server = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            loop {
+                let (len, peer) = server.recv_from(&mut buf).unwrap();
+                let packet = &buf[..len];
+                let response = if packet.starts_with(&client_magic) {
+                    let client_key = box_::PublicKey::from_slice(&packet[8..40]).unwrap();
+                    let shared_key = box_::precompute(&client_key, &resolver_secret);
+                    let mut nonce = [0u8; box_::NONCEBYTES];
+                    nonce[..HALF_NONCE_LEN].copy_from_slice(&packet[40..52]);
+                    let padded =
+                        box_::open_precomputed(&packet[52..], &box_::Nonce(nonce), &shared_key)
+                            .unwrap();
+                    let query = Message::from_vec(unpad(&padded).unwrap()).unwrap();
+
+                    let mut answer = query.clone();
+                    answer.set_message_type(MessageType::Response);
+                    let mut record =
+                        Record::with(query.queries()[0].name().clone(), RecordType::A, 60);
+                    record.set_rdata(RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+                    answer.add_answer(record);
+
+                    randombytes_into(&mut nonce[HALF_NONCE_LEN..]);
+                    let mut response = RESOLVER_MAGIC.to_vec();
+                    response.extend(&nonce);
+                    response.extend(box_::seal_precomputed(
+                        &pad(&answer.to_vec().unwrap(), 0),
+                        &box_::Nonce(nonce),
+                        &shared_key,
+                    ));
+                    response
+                } else {
+                    let query = Message::from_vec(packet).unwrap();
+                    let mut answer = query.clone();
+                    answer.set_message_type(MessageType::Response);
+                    let mut record = Record::with(provider_name.clone(), RecordType::TXT, 60);
+                    record.set_rdata(RData::TXT(TXT::from_bytes(vec![&cert[..]])));
+                    answer.add_answer(record);
+                    answer.to_vec().unwrap()
+                };
+                server.send_to(&response, peer).unwrap();
+            }
+        });
+        (addr, provider_key)
+    }
+
+    #[test]
+    fn encrypted_query() {
+        sodiumoxide::init().unwrap();
+        let (addr, provider_key) = spawn_server("2.dnscrypt-cert.example.");
+        let stamp = stamp_string(&addr.to_string(), &provider_key, "2.dnscrypt-cert.example")
+            .parse()
+            .unwrap();
+
+        let mut runtime = Runtime::new().expect("Unable to create a tokio runtime");
+        let resolver = runtime
+            .block_on(future::lazy(|| {
+                DnscryptResolver::with_options(stamp, Duration::from_secs(5), Default::default())
+            }))
+            .unwrap();
+        let name = Name::from_str("example.com.").unwrap();
+        let resp = runtime
+            .block_on(resolver.query(Query::query(name, RecordType::A)))
+            .unwrap();
+        let expected: IpAddr = [192, 0, 2, 1].into();
+        assert!(resp
+            .answers()
+            .iter()
+            .any(|record| record.rdata().to_ip_addr() == Some(expected)));
+    }
+}
@@ -0,0 +1,12 @@
+//! DNSCrypt version 2 (https://dnscrypt.info/protocol) with X25519-XSalsa20Poly1305,
+//! built with the `dnscrypt` feature. Without it, DNSCrypt upstreams are rejected
+//! when the config is loaded.
+
+#[cfg(feature = "dnscrypt")]
+mod client;
+
+#[cfg(not(feature = "dnscrypt"))]
+#[path = "none.rs"]
+mod client;
+
+pub use self::client::{DnscryptResolver, Stamp};
@@ -0,0 +1,116 @@
+//! DNSSEC validation of the answers from upstreams, built with the `dnssec` feature.
+//! Without it, enabling the validation is rejected when the dispatcher is built.
+
+use std::str::FromStr;
+
+use failure::{err_msg, Error};
+use serde_derive::{Deserialize, Serialize};
+
+#[cfg(feature = "dnssec")]
+mod validator;
+
+#[cfg(not(feature = "dnssec"))]
+#[path = "none.rs"]
+mod validator;
+
+pub use self::validator::Validator;
+
+/// The DS record of the root KSK-2017
+pub const ROOT_TRUST_ANCHOR: &str =
+    "20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D";
+
+/// The result of validating the answers of a response, from the strongest to the weakest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Status {
+    /// Every answer is signed with a chain of trust from the trust anchors
+    #[serde(rename = "secure")]
+    Secure,
+    /// Some answers are under delegations proven to be unsigned
+    #[serde(rename = "insecure")]
+    Insecure,
+    /// Some signatures or proofs are missing or invalid
+    #[serde(rename = "bogus")]
+    Bogus,
+}
+
+/// A DS record of the root zone, written as the key tag, the algorithm,
+/// the digest type and the digest in hex
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrustAnchor {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+}
+
+impl FromStr for TrustAnchor {
+    type Err = Error;
+
+    fn from_str(anchor: &str) -> Result<Self, Error> {
+        let invalid = || err_msg(format!("Invalid trust anchor: {}", anchor));
+        let mut fields = anchor.split_whitespace();
+        let key_tag = fields
+            .next()
+            .and_then(|field| field.parse::<u16>().ok())
+            .ok_or_else(invalid)?;
+        let algorithm = fields
+            .next()
+            .and_then(|field| field.parse::<u8>().ok())
+            .ok_or_else(invalid)?;
+        let digest_type = fields
+            .next()
+            .and_then(|field| field.parse::<u8>().ok())
+            .ok_or_else(invalid)?;
+        // The digest may be split by spaces
+        let digest = decode_hex(&fields.collect::<String>()).ok_or_else(invalid)?;
+        let len = match digest_type {
+            1 => 20,
+            2 => 32,
+            4 => 48,
+            _ => {
+                return Err(err_msg(format!(
+                    "Unsupported digest type {} in trust anchor",
+                    digest_type
+                )));
+            }
+        };
+        if digest.len() != len {
+            return Err(invalid());
+        }
+        Ok(TrustAnchor {
+            key_tag,
+            algorithm,
+            digest_type,
+            digest,
+        })
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_trust_anchor() {
+        let anchor: TrustAnchor = ROOT_TRUST_ANCHOR.parse().unwrap();
+        assert_eq!(anchor.key_tag, 20326);
+        assert_eq!(anchor.algorithm, 8);
+        assert_eq!(anchor.digest_type, 2);
+        assert_eq!(anchor.digest.len(), 32);
+        assert_eq!(&anchor.digest[..2], &[0xe0, 0x6d]);
+
+        assert!("20326 8 2 E06D44".parse::<TrustAnchor>().is_err());
+        assert!("20326 8 9 E06D44".parse::<TrustAnchor>().is_err());
+        assert!(". IN DS 20326 8 2".parse::<TrustAnchor>().is_err());
+    }
+}
@@ -0,0 +1,31 @@
+use super::{Status, TrustAnchor};
+
+use crate::resolver::Resolver;
+
+use failure::{err_msg, Error};
+use tokio::prelude::*;
+use trust_dns::op::DnsResponse;
+use trust_dns_proto::error::ProtoError;
+
+/// Never constructed, so nothing is validated
+#[derive(Clone)]
+pub enum Validator {}
+
+impl Validator {
+    pub fn new(_anchors: Vec<TrustAnchor>) -> Result<Self, Error> {
+        Err(err_msg(
+            "DNSSEC validation is not supported by this build. Enable the dnssec feature.",
+        ))
+    }
+
+    pub fn validate<R>(
+        &self,
+        _resolver: R,
+        _resp: DnsResponse,
+    ) -> Box<Future<Item = (DnsResponse, Status), Error = ProtoError> + Send>
+    where
+        R: Resolver + Clone + 'static,
+    {
+        match *self {}
+    }
+}
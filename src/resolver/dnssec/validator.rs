@@ -0,0 +1,542 @@
+//! Builds the chain of trust from the root zone down to the zone of each answer.
+//! DS and DNSKEY records are queried from the upstream the answers come from,
+//! with the DO and CD bits set. A missing DS record is only accepted with a signed
+//! NSEC or NSEC3 record proving an unsigned delegation.
+
+use super::{Status, TrustAnchor};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::resolver::{QueryOptions, Resolver};
+use crate::STDERR;
+
+use failure::Error;
+use parking_lot::Mutex;
+use slog::debug;
+use tokio::prelude::*;
+use trust_dns::op::{DnsResponse, Query};
+use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::rr::dns_class::DNSClass;
+use trust_dns_proto::rr::dnssec::rdata::{DNSSECRData, DNSSECRecordType, DNSKEY, DS, NSEC3, SIG};
+use trust_dns_proto::rr::dnssec::{DigestType, Verifier};
+use trust_dns_proto::rr::record_data::RData;
+use trust_dns_proto::rr::record_type::RecordType;
+use trust_dns_proto::rr::{Name, Record};
+
+const DNSKEY_TYPE: RecordType = RecordType::DNSSEC(DNSSECRecordType::DNSKEY);
+const DS_TYPE: RecordType = RecordType::DNSSEC(DNSSECRecordType::DS);
+const RRSIG_TYPE: RecordType = RecordType::DNSSEC(DNSSECRecordType::RRSIG);
+
+/// Zones are cached for the TTL of the records they are validated with, up to an hour
+const MAX_CACHE_TTL: u32 = 3600;
+
+/// Zones with a broken chain of trust are checked again after a minute
+const BOGUS_CACHE_TTL: u32 = 60;
+
+type ProtoFuture<T> = Box<Future<Item = T, Error = ProtoError> + Send>;
+
+/// What is known about the zone a name is in
+#[derive(Clone)]
+enum ZoneKeys {
+    /// The name is in the signed zone `zone`, whose keys are validated
+    Secure { zone: Name, keys: Arc<Vec<DNSKEY>> },
+    /// The name is under an unsigned delegation
+    Insecure,
+    /// The chain of trust is broken for the reason
+    Bogus(String),
+}
+
+#[derive(Clone)]
+pub struct Validator {
+    anchors: Arc<Vec<TrustAnchor>>,
+    zones: Arc<Mutex<HashMap<Name, (ZoneKeys, Instant)>>>,
+}
+
+impl Validator {
+    pub fn new(anchors: Vec<TrustAnchor>) -> Result<Self, Error> {
+        Ok(Validator {
+            anchors: Arc::new(anchors),
+            zones: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Validates the answers of `resp`, which are then stripped of their signatures.
+    /// The keys are queried from `resolver`, which `resp` comes from.
+    pub fn validate<R>(
+        &self,
+        resolver: R,
+        mut resp: DnsResponse,
+    ) -> ProtoFuture<(DnsResponse, Status)>
+    where
+        R: Resolver + Clone + 'static,
+    {
+        let mut answers = resp.take_answers();
+        let rrsets = rrsets(&answers);
+        answers.retain(|record| record.rr_type() != RRSIG_TYPE);
+        resp.insert_answers(answers);
+        if rrsets.is_empty() {
+            return Box::new(future::ok((resp, Status::Insecure)));
+        }
+
+        let checks: Vec<_> = rrsets
+            .into_iter()
+            .map(|rrset| self.check(resolver.clone(), rrset))
+            .collect();
+        Box::new(future::join_all(checks).map(move |statuses| {
+            // The weakest status of all the RRsets
+            let status = statuses.into_iter().max().unwrap_or(Status::Insecure);
+            (resp, status)
+        }))
+    }
+
+    fn check<R>(&self, resolver: R, rrset: Rrset) -> ProtoFuture<Status>
+    where
+        R: Resolver + Clone + 'static,
+    {
+        Box::new(
+            self.zone_keys(resolver, rrset.name.clone())
+                .map(move |keys| {
+                    let result = match keys {
+                        ZoneKeys::Secure { zone, keys } => rrset.verify(&zone, &keys),
+                        ZoneKeys::Insecure => return Status::Insecure,
+                        ZoneKeys::Bogus(reason) => Err(reason),
+                    };
+                    match result {
+                        Ok(()) => Status::Secure,
+                        Err(reason) => {
+                            debug!(
+                                STDERR,
+                                "{} {} is bogus: {}", rrset.name, rrset.record_type, reason
+                            );
+                            Status::Bogus
+                        }
+                    }
+                }),
+        )
+    }
+
+    /// Walks down from the root zone, so that each delegation is proven by its parent
+    fn zone_keys<R>(&self, resolver: R, name: Name) -> ProtoFuture<ZoneKeys>
+    where
+        R: Resolver + Clone + 'static,
+    {
+        if let Some(keys) = self.cached(&name) {
+            return Box::new(future::ok(keys));
+        }
+        let resolved: ProtoFuture<(ZoneKeys, u32)> = if name.is_root() {
+            self.root_keys(&resolver)
+        } else {
+            let validator = self.clone();
+            let child = name.clone();
+            Box::new(self.zone_keys(resolver.clone(), name.base_name()).and_then(
+                move |parent| -> ProtoFuture<(ZoneKeys, u32)> {
+                    match parent {
+                        ZoneKeys::Secure { zone, keys } => {
+                            validator.delegation(&resolver, child, zone, keys)
+                        }
+                        unsigned_or_bogus => {
+                            Box::new(future::ok((unsigned_or_bogus, MAX_CACHE_TTL)))
+                        }
+                    }
+                },
+            ))
+        };
+        let zones = self.zones.clone();
+        Box::new(resolved.map(move |(keys, ttl)| {
+            let ttl = match keys {
+                ZoneKeys::Bogus(_) => BOGUS_CACHE_TTL,
+                _ => ttl.min(MAX_CACHE_TTL),
+            };
+            let expires = Instant::now() + Duration::from_secs(u64::from(ttl));
+            zones.lock().insert(name, (keys.clone(), expires));
+            keys
+        }))
+    }
+
+    fn cached(&self, name: &Name) -> Option<ZoneKeys> {
+        let mut zones = self.zones.lock();
+        match zones.get(name) {
+            Some((keys, expires)) if Instant::now() < *expires => return Some(keys.clone()),
+            Some(_) => {}
+            None => return None,
+        }
+        zones.remove(name);
+        None
+    }
+
+    /// The keys of the root zone must match one of the trust anchors
+    fn root_keys<R: Resolver>(&self, resolver: &R) -> ProtoFuture<(ZoneKeys, u32)> {
+        let anchors = self.anchors.clone();
+        Box::new(query(resolver, Name::root(), DNSKEY_TYPE).map(move |resp| {
+            let root = Name::root();
+            match find(&rrsets(resp.answers()), &root, DNSKEY_TYPE) {
+                Some(rrset) => {
+                    let trusted: Vec<_> = rrset
+                        .dnskeys()
+                        .into_iter()
+                        .filter(|key| {
+                            anchors.iter().any(|anchor| {
+                                DigestType::from_u8(anchor.digest_type)
+                                    .map(|digest_type| {
+                                        matches_digest(&root, key, digest_type, &anchor.digest)
+                                    })
+                                    .unwrap_or(false)
+                            })
+                        })
+                        .collect();
+                    (rrset.key_set(&root, &trusted), rrset.ttl())
+                }
+                None => (
+                    ZoneKeys::Bogus("no DNSKEY records for the root zone".to_owned()),
+                    0,
+                ),
+            }
+        }))
+    }
+
+    /// Checks if `name` is a signed or unsigned delegation from the signed zone `parent`.
+    /// If it is not a delegation at all, it is in the parent zone.
+    fn delegation<R>(
+        &self,
+        resolver: &R,
+        name: Name,
+        parent: Name,
+        parent_keys: Arc<Vec<DNSKEY>>,
+    ) -> ProtoFuture<(ZoneKeys, u32)>
+    where
+        R: Resolver + Clone + 'static,
+    {
+        let resolver = resolver.clone();
+        Box::new(query(&resolver, name.clone(), DS_TYPE).and_then(
+            move |resp| -> ProtoFuture<(ZoneKeys, u32)> {
+                let answers = rrsets(resp.answers());
+                if let Some(ds) = find(&answers, &name, DS_TYPE) {
+                    if let Err(reason) = ds.verify(&parent, &parent_keys) {
+                        let reason = format!("DS of {}: {}", name, reason);
+                        return Box::new(future::ok((ZoneKeys::Bogus(reason), 0)));
+                    }
+                    let ds_records = ds.ds_records();
+                    let ttl = ds.ttl();
+                    return Box::new(query(&resolver, name.clone(), DNSKEY_TYPE).map(
+                        move |resp| match find(&rrsets(resp.answers()), &name, DNSKEY_TYPE) {
+                            Some(rrset) => {
+                                let trusted: Vec<_> = rrset
+                                    .dnskeys()
+                                    .into_iter()
+                                    .filter(|key| {
+                                        ds_records.iter().any(|ds| {
+                                            matches_digest(
+                                                &name,
+                                                key,
+                                                ds.digest_type(),
+                                                ds.digest(),
+                                            )
+                                        })
+                                    })
+                                    .collect();
+                                (rrset.key_set(&name, &trusted), ttl.min(rrset.ttl()))
+                            }
+                            None => {
+                                let reason = format!("no DNSKEY records for {}", name);
+                                (ZoneKeys::Bogus(reason), 0)
+                            }
+                        },
+                    ));
+                }
+
+                // Anything signed by the parent zone shows that the name is still in it,
+                // unless an NSEC or NSEC3 record proves an unsigned delegation
+                let authority = rrsets(resp.name_servers());
+                let proofs: Vec<_> = answers
+                    .iter()
+                    .chain(&authority)
+                    .filter(|rrset| rrset.verify(&parent, &parent_keys).is_ok())
+                    .collect();
+                let ttl = proofs.iter().map(|rrset| rrset.ttl()).min().unwrap_or(0);
+                let keys = if proofs.is_empty() {
+                    ZoneKeys::Bogus(format!("no signed proof that {} has no DS records", name))
+                } else if proofs
+                    .iter()
+                    .any(|rrset| proves_unsigned_delegation(&name, rrset))
+                {
+                    ZoneKeys::Insecure
+                } else {
+                    ZoneKeys::Secure {
+                        zone: parent,
+                        keys: parent_keys,
+                    }
+                };
+                Box::new(future::ok((keys, ttl)))
+            },
+        ))
+    }
+}
+
+fn query<R: Resolver>(
+    resolver: &R,
+    name: Name,
+    record_type: RecordType,
+) -> ProtoFuture<DnsResponse> {
+    let options = QueryOptions {
+        dnssec: true,
+        ..QueryOptions::default()
+    };
+    resolver.query_with(Query::query(name, record_type), options)
+}
+
+/// Records of the same name and type, with the signatures covering them
+struct Rrset {
+    name: Name,
+    record_type: RecordType,
+    dns_class: DNSClass,
+    records: Vec<Record>,
+    sigs: Vec<SIG>,
+}
+
+impl Rrset {
+    fn ttl(&self) -> u32 {
+        self.records.iter().map(Record::ttl).min().unwrap_or(0)
+    }
+
+    fn dnskeys(&self) -> Vec<DNSKEY> {
+        self.records
+            .iter()
+            .filter_map(|record| match record.rdata() {
+                RData::DNSSEC(DNSSECRData::DNSKEY(key)) => Some(key.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn ds_records(&self) -> Vec<DS> {
+        self.records
+            .iter()
+            .filter_map(|record| match record.rdata() {
+                RData::DNSSEC(DNSSECRData::DS(ds)) => Some(ds.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Succeeds if one of the signatures by `signer` is in its validity period
+    /// and made by one of the keys
+    fn verify(&self, signer: &Name, keys: &[DNSKEY]) -> Result<(), String> {
+        if self.sigs.is_empty() {
+            return Err("not signed".to_owned());
+        }
+        let now = now();
+        let valid = self
+            .sigs
+            .iter()
+            .filter(|sig| {
+                sig.signer_name() == signer
+                    && sig.sig_inception() <= now
+                    && now <= sig.sig_expiration()
+            })
+            .any(|sig| {
+                keys.iter()
+                    .filter(|key| key.algorithm() == sig.algorithm())
+                    .any(|key| {
+                        key.verify_rrsig(&self.name, self.dns_class, sig, &self.records)
+                            .is_ok()
+                    })
+            });
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("no valid signature by {}", signer))
+        }
+    }
+
+    /// The DNSKEY records of `zone`, if they are signed by one of the trusted keys
+    fn key_set(&self, zone: &Name, trusted: &[DNSKEY]) -> ZoneKeys {
+        if trusted.is_empty() {
+            return ZoneKeys::Bogus(format!(
+                "no DNSKEY of {} matches the trust anchors or DS records",
+                zone
+            ));
+        }
+        match self.verify(zone, trusted) {
+            Ok(()) => ZoneKeys::Secure {
+                zone: zone.clone(),
+                keys: Arc::new(
+                    self.dnskeys()
+                        .into_iter()
+                        .filter(|key| key.zone_key() && !key.revoke())
+                        .collect(),
+                ),
+            },
+            Err(reason) => ZoneKeys::Bogus(format!("DNSKEY of {}: {}", zone, reason)),
+        }
+    }
+}
+
+/// Groups the records into RRsets. RRSIG records are attached to the RRsets they cover.
+fn rrsets(records: &[Record]) -> Vec<Rrset> {
+    let mut sets: Vec<Rrset> = Vec::new();
+    for record in records {
+        let (record_type, sig) = match record.rdata() {
+            RData::DNSSEC(DNSSECRData::SIG(sig)) if record.rr_type() == RRSIG_TYPE => {
+                (sig.type_covered(), Some(sig))
+            }
+            _ => (record.rr_type(), None),
+        };
+        let index = match sets
+            .iter()
+            .position(|set| set.name == *record.name() && set.record_type == record_type)
+        {
+            Some(index) => index,
+            None => {
+                sets.push(Rrset {
+                    name: record.name().clone(),
+                    record_type,
+                    dns_class: record.dns_class(),
+                    records: Vec::new(),
+                    sigs: Vec::new(),
+                });
+                sets.len() - 1
+            }
+        };
+        match sig {
+            Some(sig) => sets[index].sigs.push(sig.clone()),
+            None => sets[index].records.push(record.clone()),
+        }
+    }
+    sets.retain(|set| !set.records.is_empty());
+    sets
+}
+
+fn find<'a>(rrsets: &'a [Rrset], name: &Name, record_type: RecordType) -> Option<&'a Rrset> {
+    rrsets
+        .iter()
+        .find(|rrset| rrset.name == *name && rrset.record_type == record_type)
+}
+
+fn matches_digest(owner: &Name, key: &DNSKEY, digest_type: DigestType, digest: &[u8]) -> bool {
+    key.to_digest(owner, digest_type)
+        .map(|computed| computed.as_ref() == digest)
+        .unwrap_or(false)
+}
+
+/// An NSEC or NSEC3 record of `name` with NS but no DS, or an opt-out NSEC3 record covering it
+fn proves_unsigned_delegation(name: &Name, rrset: &Rrset) -> bool {
+    rrset.records.iter().any(|record| match record.rdata() {
+        RData::DNSSEC(DNSSECRData::NSEC(nsec)) => {
+            record.name() == name && is_unsigned_delegation(nsec.type_bit_maps())
+        }
+        RData::DNSSEC(DNSSECRData::NSEC3(nsec3)) => nsec3_proves(name, record.name(), nsec3),
+        _ => false,
+    })
+}
+
+fn is_unsigned_delegation(types: &[RecordType]) -> bool {
+    types.contains(&RecordType::NS)
+        && !types.contains(&DS_TYPE)
+        && !types.contains(&RecordType::SOA)
+}
+
+fn nsec3_proves(name: &Name, owner: &Name, nsec3: &NSEC3) -> bool {
+    let hash = match nsec3
+        .hash_algorithm()
+        .hash(nsec3.salt(), name, nsec3.iterations())
+    {
+        Ok(hash) => hash,
+        Err(_) => return false,
+    };
+    let owner_hash = match owner.iter().next().and_then(decode_base32hex) {
+        Some(owner_hash) => owner_hash,
+        None => return false,
+    };
+    if hash.as_ref() == &owner_hash[..] {
+        return is_unsigned_delegation(nsec3.type_bit_maps());
+    }
+    nsec3.opt_out() && covers(&owner_hash, nsec3.next_hashed_owner_name(), hash.as_ref())
+}
+
+/// Whether `hash` is between the owner and the next hashed owner of an NSEC3 record.
+/// The last record in a zone wraps around to the first.
+fn covers(owner: &[u8], next: &[u8], hash: &[u8]) -> bool {
+    if owner < next {
+        owner < hash && hash < next
+    } else {
+        owner < hash || hash < next
+    }
+}
+
+/// Decodes the hashed owner names of NSEC3 records (RFC 4648, without padding)
+fn decode_base32hex(label: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(label.len() * 5 / 8);
+    let (mut bits, mut len) = (0u32, 0u32);
+    for &c in label {
+        let value = match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'v' => c - b'a' + 10,
+            b'A'..=b'V' => c - b'A' + 10,
+            _ => return None,
+        };
+        bits = bits << 5 | u32::from(value);
+        len += 5;
+        if len >= 8 {
+            len -= 8;
+            decoded.push((bits >> len) as u8);
+            bits &= (1 << len) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nsec3_hashes() {
+        // From the examples of RFC 5155
+        assert_eq!(
+            decode_base32hex(b"0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"),
+            Some(vec![
+                0x06, 0x53, 0x68, 0xab, 0xee, 0xd7, 0xec, 0x6e, 0x9f, 0xeb, 0xa9, 0x6b, 0x8c, 0x8b,
+                0xc3, 0xe8, 0xb7, 0x91, 0xf7, 0x16,
+            ])
+        );
+        assert_eq!(decode_base32hex(b"not-base32"), None);
+
+        assert!(covers(&[2], &[8], &[5]));
+        assert!(!covers(&[2], &[8], &[9]));
+        assert!(covers(&[8], &[2], &[9]));
+        assert!(covers(&[8], &[2], &[1]));
+        assert!(!covers(&[8], &[2], &[5]));
+    }
+
+    #[test]
+    fn group_rrsets() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let mut a = Record::with(name.clone(), RecordType::A, 60);
+        a.set_rdata(RData::A([192, 0, 2, 1].into()));
+        let mut b = Record::with(name.clone(), RecordType::A, 30);
+        b.set_rdata(RData::A([192, 0, 2, 2].into()));
+        let mut aaaa = Record::with(name.clone(), RecordType::AAAA, 60);
+        aaaa.set_rdata(RData::AAAA("2001:db8::1".parse().unwrap()));
+
+        let sets = rrsets(&[a, aaaa, b]);
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].records.len(), 2);
+        assert_eq!(sets[0].ttl(), 30);
+        assert!(sets[0].verify(&name, &[]).is_err());
+        assert!(find(&sets, &name, RecordType::AAAA).is_some());
+        assert!(is_unsigned_delegation(&[
+            RecordType::NS,
+            RecordType::DNSSEC(DNSSECRecordType::NSEC)
+        ]));
+        assert!(!is_unsigned_delegation(&[RecordType::NS, DS_TYPE]));
+    }
+}
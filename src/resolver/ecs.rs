@@ -0,0 +1,209 @@
+//! EDNS Client Subnet (RFC 7871)
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use trust_dns_proto::rr::rdata::opt::EdnsOption;
+
+/// The option code of EDNS Client Subnet
+pub const EDNS_CLIENT_SUBNET: u16 = 8;
+
+/// An address with only the first `prefix` bits kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ClientSubnet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl ClientSubnet {
+    /// The bits after `prefix` are cleared. `prefix` is capped at the address length.
+    pub fn new(addr: IpAddr, prefix: u8) -> Self {
+        let addr = unmap(addr);
+        let prefix = prefix.min(max_prefix(&addr));
+        let addr = match addr {
+            IpAddr::V4(ip) => {
+                let mask = u32::max_value()
+                    .checked_shl(32 - u32::from(prefix))
+                    .unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::max_value()
+                    .checked_shl(128 - u32::from(prefix))
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+            }
+        };
+        ClientSubnet { addr, prefix }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// The family, the source prefix length, a zero scope prefix length
+    /// and only as many bytes of the address as the prefix covers
+    pub fn to_option(&self) -> EdnsOption {
+        let (family, octets) = match self.addr {
+            IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+        };
+        let len = (self.prefix as usize + 7) / 8;
+        let mut data = vec![0, family, self.prefix, 0];
+        data.extend(&octets[..len]);
+        EdnsOption::Unknown(EDNS_CLIENT_SUBNET, data)
+    }
+
+    pub fn from_option(option: &EdnsOption) -> Option<Self> {
+        match option {
+            EdnsOption::Unknown(EDNS_CLIENT_SUBNET, data) => Self::from_bytes(data),
+            _ => None,
+        }
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < 4 {
+            return None;
+        }
+        let prefix = data[2];
+        let address = &data[4..];
+        let addr = match (data[0], data[1]) {
+            (0, 1) if address.len() <= 4 => {
+                let mut octets = [0u8; 4];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::from(octets)
+            }
+            (0, 2) if address.len() <= 16 => {
+                let mut octets = [0u8; 16];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::from(octets)
+            }
+            _ => return None,
+        };
+        if prefix > max_prefix(&addr) {
+            return None;
+        }
+        Some(ClientSubnet::new(addr, prefix))
+    }
+}
+
+/// Decides the client subnet sent to upstreams
+#[derive(Clone, Debug, PartialEq)]
+pub enum EcsPolicy {
+    /// Sends no client subnet, even if the client gives one
+    Strip,
+    /// Sends the client subnet given by the client, if any
+    Passthrough,
+    /// Sends the address of the client, truncated to the prefix length of its family.
+    /// Nothing is sent for loopback, private and link-local addresses.
+    Client { v4_prefix: u8, v6_prefix: u8 },
+    /// Always sends the same subnet
+    Subnet(ClientSubnet),
+}
+
+impl Default for EcsPolicy {
+    fn default() -> Self {
+        EcsPolicy::Strip
+    }
+}
+
+impl EcsPolicy {
+    /// `client` is the address the query comes from, and `given` the client subnet in it
+    pub fn apply(
+        &self,
+        client: Option<IpAddr>,
+        given: Option<ClientSubnet>,
+    ) -> Option<ClientSubnet> {
+        match self {
+            EcsPolicy::Strip => None,
+            EcsPolicy::Passthrough => given,
+            EcsPolicy::Client {
+                v4_prefix,
+                v6_prefix,
+            } => match client.map(unmap) {
+                Some(addr) if is_local(&addr) => None,
+                Some(addr @ IpAddr::V4(_)) => Some(ClientSubnet::new(addr, *v4_prefix)),
+                Some(addr @ IpAddr::V6(_)) => Some(ClientSubnet::new(addr, *v6_prefix)),
+                None => None,
+            },
+            EcsPolicy::Subnet(subnet) => Some(*subnet),
+        }
+    }
+}
+
+fn max_prefix(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Turns an IPv4-mapped IPv6 address, as seen on dual-stack sockets, into IPv4
+fn unmap(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(ip) if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+            let octets = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(
+                octets[12], octets[13], octets[14], octets[15],
+            ))
+        }
+        _ => addr,
+    }
+}
+
+/// Addresses that tell nothing about where the client is
+fn is_local(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || first & 0xfe00 == 0xfc00 // unique local
+                || first & 0xffc0 == 0xfe80 // link-local
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn option_round_trip() {
+        let subnet = ClientSubnet::new("203.0.113.77".parse().unwrap(), 24);
+        let option = subnet.to_option();
+        assert_eq!(
+            option,
+            EdnsOption::Unknown(EDNS_CLIENT_SUBNET, vec![0, 1, 24, 0, 203, 0, 113])
+        );
+        assert_eq!(ClientSubnet::from_option(&option), Some(subnet));
+
+        let subnet = ClientSubnet::new("2001:db8:abcd:12ff::1".parse().unwrap(), 56);
+        assert_eq!(
+            subnet.addr(),
+            "2001:db8:abcd:1200::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(ClientSubnet::from_option(&subnet.to_option()), Some(subnet));
+    }
+
+    #[test]
+    fn client_policy() {
+        let policy = EcsPolicy::Client {
+            v4_prefix: 24,
+            v6_prefix: 56,
+        };
+        assert_eq!(
+            policy.apply(Some("::ffff:198.51.100.20".parse().unwrap()), None),
+            Some(ClientSubnet::new("198.51.100.0".parse().unwrap(), 24))
+        );
+        assert_eq!(
+            policy.apply(Some("192.168.1.2".parse().unwrap()), None),
+            None
+        );
+        assert_eq!(policy.apply(Some("fd00::1".parse().unwrap()), None), None);
+    }
+}
@@ -0,0 +1,115 @@
+use super::*;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::health::Health;
+
+/// Failed queries are counted as if they took this many seconds
+const FAILURE_PENALTY_SECS: u64 = 5;
+
+/// Wraps a resolver and keeps an estimate of its latency,
+/// which is an exponentially weighted moving average of the query time.
+/// The outcome of every query is also reported to the health state if there is one.
+#[derive(Clone)]
+pub struct MeasuredResolver {
+    inner: Arc<Resolver>,
+    // In microseconds. Zero means no query has finished yet.
+    latency: Arc<AtomicUsize>,
+    health: Option<Arc<Health>>,
+}
+
+impl MeasuredResolver {
+    pub fn new(inner: Arc<Resolver>) -> Self {
+        MeasuredResolver {
+            inner,
+            latency: Arc::new(AtomicUsize::new(0)),
+            health: None,
+        }
+    }
+
+    pub fn with_health(self, health: Health) -> Self {
+        MeasuredResolver {
+            health: Some(Arc::new(health)),
+            ..self
+        }
+    }
+
+    /// Upstreams without health checks are always considered healthy.
+    pub fn is_healthy(&self) -> bool {
+        self.health
+            .as_ref()
+            .map(|health| health.is_healthy())
+            .unwrap_or(true)
+    }
+
+    /// Returns `None` if no query has finished yet.
+    pub fn latency(&self) -> Option<Duration> {
+        match self.latency.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros as u64)),
+        }
+    }
+
+    fn record(latency: &AtomicUsize, sample: Duration) {
+        let sample = (sample.as_secs() as usize)
+            .saturating_mul(1_000_000)
+            .saturating_add(sample.subsec_micros() as usize)
+            .max(1);
+        // Concurrent updates may overwrite each other, which is fine for an estimate.
+        let old = latency.load(Ordering::Relaxed);
+        let new = if old == 0 {
+            sample
+        } else {
+            old - old / 8 + sample / 8
+        };
+        latency.store(new.max(1), Ordering::Relaxed);
+    }
+}
+
+impl Resolver for MeasuredResolver {
+    fn query_with(
+        &self,
+        query: Query,
+        options: QueryOptions,
+    ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
+        let latency = self.latency.clone();
+        let health = self.health.clone();
+        let start = Instant::now();
+        Box::new(self.inner.query_with(query, options).then(move |res| {
+            let sample = match res {
+                Ok(_) => start.elapsed(),
+                Err(_) => start
+                    .elapsed()
+                    .max(Duration::from_secs(FAILURE_PENALTY_SECS)),
+            };
+            Self::record(&latency, sample);
+            if let Some(health) = health {
+                match res {
+                    Ok(_) => health.report_success(),
+                    Err(_) => health.report_failure(),
+                }
+            }
+            res
+        }))
+    }
+
+    fn connection_state(&self) -> Option<&'static str> {
+        self.inner.connection_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moving_average() {
+        let latency = AtomicUsize::new(0);
+        MeasuredResolver::record(&latency, Duration::from_millis(80));
+        assert_eq!(latency.load(Ordering::Relaxed), 80_000);
+        MeasuredResolver::record(&latency, Duration::from_millis(160));
+        assert_eq!(latency.load(Ordering::Relaxed), 90_000);
+    }
+}
@@ -1,18 +1,97 @@
+use self::ecs::ClientSubnet;
+
 use tokio::prelude::*;
 use trust_dns::op::{DnsResponse, Query};
 use trust_dns_proto::error::ProtoError;
-use trust_dns_proto::xfer::DnsRequestOptions;
+use trust_dns_proto::op::{Edns, Message, MessageType, OpCode};
+use trust_dns_proto::rr::rdata::opt::EdnsOption;
+use trust_dns_proto::xfer::{DnsRequest, DnsRequestOptions};
 
 pub trait Resolver: Send + Sync {
     fn query(
         &self,
         query: Query,
+    ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
+        self.query_with(query, QueryOptions::default())
+    }
+
+    fn query_with(
+        &self,
+        query: Query,
+        options: QueryOptions,
     ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send>;
+
+    /// The state of the connection for connection-oriented resolvers
+    fn connection_state(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// What is sent along with a query besides the question
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct QueryOptions {
+    /// Sent in the EDNS Client Subnet option
+    pub client_subnet: Option<ClientSubnet>,
+    /// Sets the DO and CD bits, so that the signatures are returned for yadd to validate
+    pub dnssec: bool,
+}
+
+impl QueryOptions {
+    /// Whether the query can be sent with `lookup` of trust-dns
+    fn is_default(&self) -> bool {
+        *self == QueryOptions::default()
+    }
+
+    fn edns_options(&self) -> Vec<EdnsOption> {
+        self.client_subnet
+            .iter()
+            .map(|subnet| subnet.to_option())
+            .collect()
+    }
 }
 
 const DNS_OPTIONS: DnsRequestOptions = DnsRequestOptions {
     expects_multiple_responses: false,
 };
 
+/// The EDNS payload size, the same as trust-dns uses in `lookup`
+const MAX_PAYLOAD_LEN: u16 = 1500 - 40 - 8;
+
+/// Builds the request `lookup` would send, with the options and extra EDNS options
+fn request(query: &Query, options: &QueryOptions, edns_options: Vec<EdnsOption>) -> DnsRequest {
+    DnsRequest::new(message(query, options, edns_options), DNS_OPTIONS)
+}
+
+/// The message of `request`, without an ID
+fn message(query: &Query, options: &QueryOptions, edns_options: Vec<EdnsOption>) -> Message {
+    let mut edns = Edns::new();
+    edns.set_max_payload(MAX_PAYLOAD_LEN);
+    edns.set_version(0);
+    edns.set_dnssec_ok(options.dnssec);
+    for option in options.edns_options().into_iter().chain(edns_options) {
+        edns.set_option(option);
+    }
+
+    let mut message = Message::new();
+    message
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .set_checking_disabled(options.dnssec)
+        .add_query(query.clone())
+        .set_edns(edns);
+    message
+}
+
+pub mod bootstrap;
+pub mod connect;
+pub mod dnscrypt;
+pub mod dnssec;
+pub mod ecs;
+pub mod measured;
+pub mod proxy;
+pub mod retry;
+pub mod socket;
 pub mod tcp;
+pub mod tls;
 pub mod udp;
@@ -0,0 +1,307 @@
+//! Tunnels the TCP connections to upstreams through SOCKS5 (RFC 1928) or HTTP CONNECT proxies.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use failure::{err_msg, Error};
+use tokio::io::{read_exact, write_all};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+/// The longest response header accepted from an HTTP proxy
+const MAX_HTTP_HEADER: usize = 8192;
+
+type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyProtocol {
+    Socks5,
+    Http,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Proxy {
+    pub protocol: ProxyProtocol,
+    pub addr: SocketAddr,
+    credentials: Option<(String, String)>,
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Leaves out the password
+        f.debug_struct("Proxy")
+            .field("protocol", &self.protocol)
+            .field("addr", &self.addr)
+            .field("user", &self.credentials.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+/// The destination the proxy is asked to connect to
+#[derive(Clone, Debug)]
+pub enum Target {
+    Addr(SocketAddr),
+    /// Resolved by the proxy
+    Name(String, u16),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Addr(addr) => write!(f, "{}", addr),
+            Target::Name(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Parses `socks5://[user:password@]ip[:port]` or `http://[user:password@]ip[:port]`
+impl FromStr for Proxy {
+    type Err = Error;
+
+    fn from_str(url: &str) -> Result<Self, Error> {
+        let invalid = |reason: &str| err_msg(format!("Invalid proxy {}: {}", url, reason));
+        let (protocol, default_port, rest) = if url.starts_with("socks5://") {
+            (ProxyProtocol::Socks5, 1080, &url["socks5://".len()..])
+        } else if url.starts_with("http://") {
+            (ProxyProtocol::Http, 80, &url["http://".len()..])
+        } else {
+            return Err(invalid("the scheme must be socks5 or http"));
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, host) = match rest.rfind('@') {
+            Some(i) => {
+                let (user, password) = match rest[..i].find(':') {
+                    Some(j) => (&rest[..j], &rest[j + 1..i]),
+                    None => (&rest[..i], ""),
+                };
+                // Both are sent with a one byte length in SOCKS5
+                if user.is_empty() || user.len() > 255 || password.len() > 255 {
+                    return Err(invalid("the user and password must be 1 to 255 bytes"));
+                }
+                (Some((user.to_owned(), password.to_owned())), &rest[i + 1..])
+            }
+            None => (None, rest),
+        };
+        let addr = match host.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => SocketAddr::new(
+                host.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<IpAddr>()
+                    .map_err(|_| invalid("the proxy must be given by an IP address"))?,
+                default_port,
+            ),
+        };
+        Ok(Proxy {
+            protocol,
+            addr,
+            credentials,
+        })
+    }
+}
+
+impl Proxy {
+    /// Asks the proxy on `stream` to connect to `target`.
+    /// The stream is returned once it is tunneled to the target.
+    pub fn handshake(&self, stream: TcpStream, target: Target) -> IoFuture<TcpStream> {
+        match self.protocol {
+            ProxyProtocol::Socks5 => Box::new(self.socks5(stream, target)),
+            ProxyProtocol::Http => Box::new(self.http(stream, target)),
+        }
+    }
+
+    fn socks5(
+        &self,
+        stream: TcpStream,
+        target: Target,
+    ) -> impl Future<Item = TcpStream, Error = io::Error> {
+        // Username/password authentication (RFC 1929) or none
+        let method = if self.credentials.is_some() { 2 } else { 0 };
+        let credentials = self.credentials.clone();
+        write_all(stream, [5, 1, method])
+            .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+            .and_then(move |(stream, reply)| -> IoFuture<TcpStream> {
+                if reply[0] != 5 {
+                    return Box::new(future::err(proxy_error("not a SOCKS5 proxy")));
+                }
+                if reply[1] != method {
+                    return Box::new(future::err(proxy_error(
+                        "the SOCKS5 proxy rejected the authentication method",
+                    )));
+                }
+                match credentials {
+                    Some((user, password)) => {
+                        let mut request = vec![1, user.len() as u8];
+                        request.extend(user.as_bytes());
+                        request.push(password.len() as u8);
+                        request.extend(password.as_bytes());
+                        Box::new(
+                            write_all(stream, request)
+                                .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+                                .and_then(|(stream, reply)| {
+                                    if reply[1] == 0 {
+                                        Ok(stream)
+                                    } else {
+                                        Err(proxy_error("SOCKS5 authentication failed"))
+                                    }
+                                }),
+                        )
+                    }
+                    None => Box::new(future::ok(stream)),
+                }
+            })
+            .and_then(move |stream| write_all(stream, socks5_request(&target)))
+            // The version, the reply code, a reserved byte, the address type
+            // and the first byte of the bound address
+            .and_then(|(stream, _)| read_exact(stream, [0u8; 5]))
+            .and_then(|(stream, reply)| {
+                if reply[1] != 0 {
+                    return Err(proxy_error(format!(
+                        "SOCKS5 proxy failed to connect: {}",
+                        socks5_reply(reply[1])
+                    )));
+                }
+                // The rest of the bound address and the port
+                let remaining = match reply[3] {
+                    1 => 3 + 2,
+                    3 => reply[4] as usize + 2,
+                    4 => 15 + 2,
+                    _ => return Err(proxy_error("invalid SOCKS5 reply")),
+                };
+                Ok((stream, remaining))
+            })
+            .and_then(|(stream, remaining)| read_exact(stream, vec![0u8; remaining]))
+            .map(|(stream, _)| stream)
+    }
+
+    fn http(
+        &self,
+        stream: TcpStream,
+        target: Target,
+    ) -> impl Future<Item = TcpStream, Error = io::Error> {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some((user, password)) = &self.credentials {
+            request += &format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(&format!("{}:{}", user, password))
+            );
+        }
+        request += "\r\n";
+        write_all(stream, request.into_bytes())
+            .and_then(|(stream, _)| {
+                // Reads byte by byte so that nothing after the header is consumed
+                future::loop_fn((stream, Vec::new()), |(stream, mut header)| {
+                    read_exact(stream, [0u8; 1]).and_then(move |(stream, byte)| {
+                        header.push(byte[0]);
+                        if header.ends_with(b"\r\n\r\n") {
+                            Ok(future::Loop::Break((stream, header)))
+                        } else if header.len() > MAX_HTTP_HEADER {
+                            Err(proxy_error("HTTP proxy response header is too long"))
+                        } else {
+                            Ok(future::Loop::Continue((stream, header)))
+                        }
+                    })
+                })
+            })
+            .and_then(|(stream, header)| {
+                let header = String::from_utf8_lossy(&header);
+                let status = header.lines().next().unwrap_or_default();
+                match status.split_whitespace().nth(1) {
+                    Some(code) if code.starts_with('2') => Ok(stream),
+                    _ => Err(proxy_error(format!(
+                        "HTTP proxy failed to connect: {}",
+                        status
+                    ))),
+                }
+            })
+    }
+}
+
+fn socks5_request(target: &Target) -> Vec<u8> {
+    let mut request = vec![5, 1, 0];
+    let port = match target {
+        Target::Addr(addr) => {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    request.push(1);
+                    request.extend(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    request.push(4);
+                    request.extend(&ip.octets());
+                }
+            }
+            addr.port()
+        }
+        Target::Name(host, port) => {
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend(host.as_bytes());
+            *port
+        }
+    };
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+    request
+}
+
+fn socks5_reply(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn proxy_error<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proxy() {
+        let proxy: Proxy = "socks5://127.0.0.1:1080".parse().unwrap();
+        assert_eq!(proxy.protocol, ProxyProtocol::Socks5);
+        assert_eq!(proxy.addr, "127.0.0.1:1080".parse().unwrap());
+        assert_eq!(proxy.credentials, None);
+
+        let proxy: Proxy = "http://user:p@ss@[::1]".parse().unwrap();
+        assert_eq!(proxy.protocol, ProxyProtocol::Http);
+        assert_eq!(proxy.addr, "[::1]:80".parse().unwrap());
+        assert_eq!(
+            proxy.credentials,
+            Some(("user".to_owned(), "p@ss".to_owned()))
+        );
+
+        assert!("socks4://127.0.0.1".parse::<Proxy>().is_err());
+        assert!("socks5://proxy.example:1080".parse::<Proxy>().is_err());
+    }
+
+    #[test]
+    fn socks5_connect_request() {
+        let target = Target::Addr("1.1.1.1:853".parse().unwrap());
+        assert_eq!(
+            socks5_request(&target),
+            vec![5, 1, 0, 1, 1, 1, 1, 1, 0x03, 0x55]
+        );
+        let target = Target::Name("dns.google".to_owned(), 53);
+        let mut expected = vec![5, 1, 0, 3, 10];
+        expected.extend(b"dns.google");
+        expected.extend(&[0, 53]);
+        assert_eq!(socks5_request(&target), expected);
+    }
+}
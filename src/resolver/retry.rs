@@ -0,0 +1,75 @@
+use super::*;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::STDERR;
+
+use slog::debug;
+use tokio::timer::Delay;
+
+/// Retries failed queries after an interval
+pub struct RetryResolver {
+    inner: Arc<Resolver>,
+    retries: usize,
+    interval: Duration,
+}
+
+impl RetryResolver {
+    pub fn new(inner: Arc<Resolver>, retries: usize, interval: Duration) -> Self {
+        RetryResolver {
+            inner,
+            retries,
+            interval,
+        }
+    }
+
+    fn attempt(
+        inner: Arc<Resolver>,
+        query: Query,
+        options: QueryOptions,
+        retries: usize,
+        interval: Duration,
+    ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
+        Box::new(
+            inner
+                .query_with(query.clone(), options.clone())
+                .or_else(move |e| {
+                    if retries == 0 {
+                        return Box::new(future::err(e))
+                            as Box<
+                                Future<Item = DnsResponse, Error = ProtoError> + 'static + Send,
+                            >;
+                    }
+                    debug!(STDERR, "{}. Retry in {:?}.", e, interval);
+                    Box::new(
+                        Delay::new(Instant::now() + interval)
+                            .map_err(ProtoError::from)
+                            .and_then(move |()| {
+                                Self::attempt(inner, query, options, retries - 1, interval)
+                            }),
+                    )
+                }),
+        )
+    }
+}
+
+impl Resolver for RetryResolver {
+    fn query_with(
+        &self,
+        query: Query,
+        options: QueryOptions,
+    ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
+        Self::attempt(
+            self.inner.clone(),
+            query,
+            options,
+            self.retries,
+            self.interval,
+        )
+    }
+
+    fn connection_state(&self) -> Option<&'static str> {
+        self.inner.connection_state()
+    }
+}
@@ -0,0 +1,122 @@
+//! Creates the sockets to upstreams with a source address, an interface or a fwmark,
+//! so that policy routing can pick the link the queries go out of.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use net2::{TcpBuilder, UdpBuilder};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SocketOptions {
+    /// The source address
+    pub bind_address: Option<IpAddr>,
+    /// Set with SO_BINDTODEVICE, Linux only
+    pub interface: Option<String>,
+    /// Set with SO_MARK, Linux only
+    pub fwmark: Option<u32>,
+}
+
+impl SocketOptions {
+    pub fn is_default(&self) -> bool {
+        *self == SocketOptions::default()
+    }
+
+    /// Whether `addr` can be reached from the source address
+    pub fn accepts(&self, addr: &SocketAddr) -> bool {
+        self.bind_address
+            .map(|ip| ip.is_ipv4() == addr.is_ipv4())
+            .unwrap_or(true)
+    }
+
+    pub fn connect(
+        &self,
+        addr: &SocketAddr,
+    ) -> Box<Future<Item = TcpStream, Error = io::Error> + Send> {
+        if self.is_default() {
+            return Box::new(TcpStream::connect(addr));
+        }
+        let stream = if addr.is_ipv4() {
+            TcpBuilder::new_v4()
+        } else {
+            TcpBuilder::new_v6()
+        }
+        .and_then(|builder| {
+            self.configure(&builder)?;
+            builder.bind(self.local_addr(addr))?;
+            builder.to_tcp_stream()
+        });
+        match stream {
+            Ok(stream) => Box::new(TcpStream::connect_std(stream, addr, &Handle::default())),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// A socket bound to a random port to send queries to `peer`
+    pub fn bind_udp(&self, peer: &SocketAddr) -> io::Result<UdpSocket> {
+        let builder = if peer.is_ipv4() {
+            UdpBuilder::new_v4()?
+        } else {
+            UdpBuilder::new_v6()?
+        };
+        self.configure(&builder)?;
+        builder.bind(self.local_addr(peer))
+    }
+
+    fn local_addr(&self, peer: &SocketAddr) -> SocketAddr {
+        let ip = self.bind_address.unwrap_or_else(|| {
+            if peer.is_ipv4() {
+                Ipv4Addr::UNSPECIFIED.into()
+            } else {
+                Ipv6Addr::UNSPECIFIED.into()
+            }
+        });
+        SocketAddr::new(ip, 0)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn configure<S: std::os::unix::io::AsRawFd>(&self, socket: &S) -> io::Result<()> {
+        let fd = socket.as_raw_fd();
+        if let Some(interface) = &self.interface {
+            setsockopt(
+                fd,
+                libc::SO_BINDTODEVICE,
+                interface.as_ptr() as *const libc::c_void,
+                interface.len(),
+            )?;
+        }
+        if let Some(fwmark) = self.fwmark {
+            setsockopt(
+                fd,
+                libc::SO_MARK,
+                &fwmark as *const u32 as *const libc::c_void,
+                std::mem::size_of::<u32>(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Interfaces and fwmarks are rejected when the config is loaded on other systems
+    #[cfg(not(target_os = "linux"))]
+    fn configure<S>(&self, _socket: &S) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt(
+    fd: libc::c_int,
+    option: libc::c_int,
+    value: *const libc::c_void,
+    len: usize,
+) -> io::Result<()> {
+    let res =
+        unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, option, value, len as libc::socklen_t) };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -1,34 +1,45 @@
 use self::ConnectionState::*;
 use super::*;
 
+use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 use std::time::Instant;
 
+use crate::metrics;
+use crate::resolver::connect::{Connector, Endpoint};
 use crate::STDERR;
 
+use futures::sync::mpsc;
 use lock_api::{RwLock, RwLockReadGuard};
-use parking_lot::RawRwLock;
+use parking_lot::{Mutex, RawRwLock};
 use slog::{debug, error, warn};
 use std::fmt::Debug;
 use std::marker::PhantomData;
-use tokio::timer::Delay;
+use tokio::net::TcpStream as TokioTcpStream;
+use tokio::timer::{Delay, Interval};
 use trust_dns::client::ClientStreamHandle;
 use trust_dns::client::{BasicClientHandle, ClientFuture};
 use trust_dns::tcp::TcpClientStream;
 use trust_dns_proto::error::ProtoError;
 use trust_dns_proto::error::ProtoErrorKind;
 use trust_dns_proto::op::Query;
-use trust_dns_proto::tcp::TcpClientConnect;
+use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use trust_dns_proto::tcp::TcpStream;
 use trust_dns_proto::xfer::dns_handle::DnsHandle;
 use trust_dns_proto::xfer::DnsClientStream;
+use trust_dns_proto::xfer::DnsRequest;
 use trust_dns_proto::xfer::DnsResponse;
+use trust_dns_proto::xfer::{BufDnsStreamHandle, BufStreamHandle};
 use trust_dns_proto::xfer::{DnsMultiplexerSerialResponse, OneshotDnsResponseReceiver};
 
 pub trait TcpDnsStreamBuilder: Clone + Debug + Sync + Send + 'static {
     type Connect: Future<Item = Self::Stream, Error = ProtoError> + Send;
     type Stream: DnsClientStream + Sync + Send + 'static;
+    /// The address shown in logs and metrics
+    fn name(&self) -> String;
     fn with_timeout(
         &self,
         timeout: Duration,
@@ -37,42 +48,105 @@ pub trait TcpDnsStreamBuilder: Clone + Debug + Sync + Send + 'static {
 
 #[derive(Clone, Debug)]
 pub struct SimpleTcpDnsStreamBuilder {
-    name_server: SocketAddr,
+    connector: Connector,
 }
 
 impl SimpleTcpDnsStreamBuilder {
     pub fn new(name_server: SocketAddr) -> Self {
-        SimpleTcpDnsStreamBuilder { name_server }
+        Self::with_connector(Connector::new(Endpoint::Addr(name_server)))
+    }
+
+    pub fn with_connector(connector: Connector) -> Self {
+        SimpleTcpDnsStreamBuilder { connector }
     }
 }
 
 impl TcpDnsStreamBuilder for SimpleTcpDnsStreamBuilder {
-    type Connect = TcpClientConnect;
-    type Stream = TcpClientStream<tokio_tcp::TcpStream>;
+    type Connect = Box<dyn Future<Item = Self::Stream, Error = ProtoError> + Send>;
+    type Stream = TcpClientStream<TokioTcpStream>;
+
+    fn name(&self) -> String {
+        self.connector.name()
+    }
+
     fn with_timeout(
         &self,
         timeout: Duration,
     ) -> (Self::Connect, Box<dyn ClientStreamHandle + 'static + Send>) {
-        TcpClientStream::with_timeout(self.name_server, timeout)
+        client_stream(self.connector.connect(timeout), self.connector.addr_hint())
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct TlsDnsStreamBuilder {
+/// Turns a stream being connected into a DNS client stream.
+/// `name_server` only labels the messages, so it needs not to be the real peer address.
+pub fn client_stream<S, F>(
+    connect: F,
     name_server: SocketAddr,
-    host: String,
+) -> (
+    Box<dyn Future<Item = TcpClientStream<S>, Error = ProtoError> + Send>,
+    Box<dyn ClientStreamHandle + 'static + Send>,
+)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    F: Future<Item = S, Error = io::Error> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::unbounded();
+    let stream = connect
+        .map(move |stream| {
+            TcpClientStream::from_stream(TcpStream::from_stream_with_receiver(
+                stream,
+                name_server,
+                receiver,
+            ))
+        })
+        .map_err(ProtoError::from);
+    let handle = BufDnsStreamHandle::new(name_server, BufStreamHandle::new(sender));
+    (Box::new(stream), Box::new(handle))
 }
 
-impl TlsDnsStreamBuilder {
-    pub fn new(name_server: SocketAddr, host: String) -> Self {
-        TlsDnsStreamBuilder { name_server, host }
+/// Settings of the connections to a TCP or TLS upstream
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    /// The number of connections queries are spread over
+    pub connections: usize,
+    /// Connections unused for this long are closed
+    pub idle_timeout: Option<Duration>,
+    /// Sends the edns-tcp-keepalive option (RFC 7828) and honours the idle timeout
+    /// advertised by the upstream
+    pub keepalive: bool,
+    /// Re-establishes connections closed by the upstream at once
+    /// instead of waiting for the next query
+    pub reconnect: bool,
+    /// A connection is replaced after carrying this many queries
+    pub max_queries: Option<usize>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            connections: 1,
+            idle_timeout: None,
+            keepalive: false,
+            reconnect: false,
+            max_queries: None,
+        }
     }
 }
 
+/// The option code of edns-tcp-keepalive
+const EDNS_TCP_KEEPALIVE: u16 = 11;
+
+/// Seconds to wait before reconnecting proactively, so that a broken upstream
+/// is not hammered with connection attempts
+const RECONNECT_DELAY_SECS: u64 = 1;
+
 pub struct TcpResolver<B: TcpDnsStreamBuilder> {
     builder: B,
     timeout: Duration,
-    state: Arc<RwLock<RawRwLock, ConnectionState>>,
+    connect_timeout: Duration,
+    options: Arc<PoolOptions>,
+    slots: Arc<Vec<Slot>>,
+    next: Arc<AtomicUsize>,
     phantom: PhantomData<B>,
 }
 
@@ -81,42 +155,195 @@ impl<B: TcpDnsStreamBuilder + Clone> Clone for TcpResolver<B> {
         TcpResolver {
             builder: self.builder.clone(),
             timeout: self.timeout,
-            state: self.state.clone(),
+            connect_timeout: self.connect_timeout,
+            options: self.options.clone(),
+            slots: self.slots.clone(),
+            next: self.next.clone(),
             phantom: PhantomData,
         }
     }
 }
 
+/// Refers to the pool without keeping it alive
+struct WeakTcpResolver<B: TcpDnsStreamBuilder> {
+    builder: B,
+    timeout: Duration,
+    connect_timeout: Duration,
+    options: Arc<PoolOptions>,
+    slots: Weak<Vec<Slot>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl<B: TcpDnsStreamBuilder> WeakTcpResolver<B> {
+    /// `None` if every `TcpResolver` sharing the pool has been dropped
+    fn upgrade(&self) -> Option<TcpResolver<B>> {
+        self.slots.upgrade().map(|slots| TcpResolver {
+            builder: self.builder.clone(),
+            timeout: self.timeout,
+            connect_timeout: self.connect_timeout,
+            options: self.options.clone(),
+            slots,
+            next: self.next.clone(),
+            phantom: PhantomData,
+        })
+    }
+}
+
 pub enum ConnectionState {
     NotConnected,
     Connecting(BasicClientHandle<DnsMultiplexerSerialResponse>),
     Connected(BasicClientHandle<DnsMultiplexerSerialResponse>),
 }
 
+/// A connection in the pool
+struct Slot {
+    state: RwLock<RawRwLock, ConnectionState>,
+    // Increased whenever a new connection is made, so that the closing of
+    // an old connection does not reset the state of the new one
+    generation: AtomicUsize,
+    // Queries sent over the current connection
+    queries: AtomicUsize,
+    last_used: Mutex<Instant>,
+    // Advertised by the upstream with edns-tcp-keepalive
+    server_idle_timeout: Mutex<Option<Duration>>,
+}
+
+impl Slot {
+    fn new() -> Self {
+        Slot {
+            state: RwLock::new(NotConnected),
+            generation: AtomicUsize::new(0),
+            queries: AtomicUsize::new(0),
+            last_used: Mutex::new(Instant::now()),
+            server_idle_timeout: Mutex::new(None),
+        }
+    }
+
+    /// Drops the handle so that the connection is closed
+    /// after the in-flight queries finish
+    fn close(&self) {
+        let mut state = self.state.write();
+        if let NotConnected = &*state {
+            return;
+        }
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        *state = NotConnected;
+    }
+
+    fn is_idle(&self, idle_timeout: Option<Duration>) -> bool {
+        let server_idle_timeout = *self.server_idle_timeout.lock();
+        let idle_timeout = match (idle_timeout, server_idle_timeout) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => match a.or(b) {
+                Some(timeout) => timeout,
+                None => return false,
+            },
+        };
+        self.last_used.lock().elapsed() >= idle_timeout
+    }
+}
+
 impl<B: TcpDnsStreamBuilder> TcpResolver<B> {
     pub fn new(builder: B) -> Self {
         Self::with_timeout(builder, Duration::from_secs(5))
     }
 
     pub fn with_timeout(builder: B, timeout: Duration) -> Self {
-        TcpResolver {
+        Self::with_options(builder, timeout, timeout / 2, PoolOptions::default())
+    }
+
+    /// Must be called inside a tokio runtime if an idle timeout is set
+    pub fn with_options(
+        builder: B,
+        timeout: Duration,
+        connect_timeout: Duration,
+        options: PoolOptions,
+    ) -> Self {
+        let slots: Vec<_> = (0..options.connections.max(1))
+            .map(|_| Slot::new())
+            .collect();
+        let resolver = TcpResolver {
             builder,
             timeout,
-            state: Arc::new(RwLock::new(NotConnected)),
+            connect_timeout,
+            options: Arc::new(options),
+            slots: Arc::new(slots),
+            next: Arc::new(AtomicUsize::new(0)),
             phantom: PhantomData,
+        };
+        if resolver.options.idle_timeout.is_some() || resolver.options.keepalive {
+            resolver.spawn_idle_check();
         }
+        resolver
     }
 
-    fn connect(&self) {
-        let mut state_ref = self.state.write();
+    /// Closes idle connections periodically until the resolver is dropped
+    fn spawn_idle_check(&self) {
+        let slots = Arc::downgrade(&self.slots);
+        let idle_timeout = self.options.idle_timeout;
+        let period = idle_timeout
+            .map(|timeout| (timeout / 2).max(Duration::from_secs(1)))
+            .unwrap_or(Duration::from_secs(1));
+        let check = Interval::new(Instant::now() + period, period)
+            .map_err(|e| warn!(STDERR, "Idle check timer error: {}", e))
+            .take_while(move |_| {
+                let slots = match slots.upgrade() {
+                    Some(slots) => slots,
+                    None => return Ok(false),
+                };
+                for slot in slots.iter().filter(|slot| slot.is_idle(idle_timeout)) {
+                    slot.close();
+                }
+                Ok(true)
+            })
+            .for_each(|_| Ok(()));
+        tokio::spawn(check);
+    }
+
+    /// Picks the next connection in turn, replacing it if it has carried too many queries
+    fn pick(&self) -> usize {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        let slot = &self.slots[index];
+        let queries = slot.queries.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(max_queries) = self.options.max_queries {
+            if queries > max_queries {
+                debug!(STDERR, "Replace connection to {:?}", self.builder);
+                slot.close();
+                slot.queries.store(1, Ordering::Relaxed);
+            }
+        }
+        *slot.last_used.lock() = Instant::now();
+        index
+    }
+
+    fn connect(&self, index: usize) {
+        let slot = &self.slots[index];
+        let mut state_ref = slot.state.write();
         match &*state_ref {
             NotConnected => {
+                metrics::TCP_RECONNECTS
+                    .with_label_values(&[&self.builder.name()])
+                    .inc();
+                let generation = slot.generation.fetch_add(1, Ordering::Relaxed) + 1;
+                slot.queries.store(0, Ordering::Relaxed);
+                *slot.server_idle_timeout.lock() = None;
+                *slot.last_used.lock() = Instant::now();
+
                 let builder = self.builder.clone();
-                let (connect, handle) = builder.with_timeout(self.timeout / 2);
-                let state = self.state.clone();
+                let (connect, handle) = builder.with_timeout(self.connect_timeout);
+                let slots = Arc::downgrade(&self.slots);
                 let stream = connect.map(move |stream| {
                     debug!(STDERR, "TCP connection to {:?} established.", builder);
-                    let mut state = state.write();
+                    let slots = match slots.upgrade() {
+                        Some(slots) => slots,
+                        None => return stream,
+                    };
+                    let slot = &slots[index];
+                    let mut state = slot.state.write();
+                    if slot.generation.load(Ordering::Relaxed) != generation {
+                        // Closed before established
+                        return stream;
+                    }
                     match &mut *state {
                         Connecting(handle) => {
                             *state = Connected(handle.clone());
@@ -136,44 +363,119 @@ impl<B: TcpDnsStreamBuilder> TcpResolver<B> {
                     ClientFuture::with_timeout(Box::new(stream), handle, self.timeout, None);
                 *state_ref = Connecting(handle);
 
-                let state = self.state.clone();
-                let builder = self.builder.clone();
-                let bg = bg.and_then(move |()| {
-                    debug!(STDERR, "TCP connection to {:?} closed", builder);
-                    *state.write() = NotConnected;
-                    future::empty()
+                // The connections must not keep the pool alive, or they would be
+                // re-established forever after the resolver is dropped
+                let weak = self.downgrade();
+                let bg = bg.then(move |_| {
+                    debug!(STDERR, "TCP connection to {:?} closed", weak.builder);
+                    let resolver = match weak.upgrade() {
+                        Some(resolver) => resolver,
+                        None => {
+                            return Box::new(future::ok(()))
+                                as Box<Future<Item = (), Error = ()> + Send>
+                        }
+                    };
+                    let slot = &resolver.slots[index];
+                    if slot.generation.load(Ordering::Relaxed) != generation {
+                        // Closed by us. A new connection may be in use already.
+                        return Box::new(future::ok(()));
+                    }
+                    *slot.state.write() = NotConnected;
+                    if !resolver.options.reconnect {
+                        return Box::new(future::ok(()));
+                    }
+                    drop(resolver);
+                    Box::new(
+                        Delay::new(Instant::now() + Duration::from_secs(RECONNECT_DELAY_SECS))
+                            .map_err(|_| ())
+                            .map(move |()| {
+                                if let Some(resolver) = weak.upgrade() {
+                                    resolver.connect(index);
+                                }
+                            }),
+                    )
                 });
 
                 tokio::spawn(bg);
             }
             _ => {}
         }
-        task::current().notify()
+    }
+
+    fn downgrade(&self) -> WeakTcpResolver<B> {
+        WeakTcpResolver {
+            builder: self.builder.clone(),
+            timeout: self.timeout,
+            connect_timeout: self.connect_timeout,
+            options: self.options.clone(),
+            slots: Arc::downgrade(&self.slots),
+            next: self.next.clone(),
+        }
+    }
+
+    fn request(&self, query: &Query, options: &QueryOptions) -> DnsRequest {
+        let mut edns_options = Vec::new();
+        if self.options.keepalive {
+            edns_options.push(EdnsOption::Unknown(EDNS_TCP_KEEPALIVE, Vec::new()));
+        }
+        request(query, options, edns_options)
+    }
+
+    /// Remembers the idle timeout advertised in the response
+    fn record_keepalive(&self, index: usize, resp: &DnsResponse) {
+        let option = resp
+            .edns()
+            .and_then(|edns| edns.option(&EdnsCode::from(EDNS_TCP_KEEPALIVE)));
+        if let Some(EdnsOption::Unknown(_, data)) = option {
+            if data.len() == 2 {
+                // In units of 100 milliseconds
+                let timeout = u64::from(u16::from(data[0]) << 8 | u16::from(data[1])) * 100;
+                *self.slots[index].server_idle_timeout.lock() =
+                    Some(Duration::from_millis(timeout));
+            }
+        }
     }
 }
 
 impl<B: TcpDnsStreamBuilder> Resolver for TcpResolver<B> {
-    fn query(
+    fn query_with(
         &self,
         query: Query,
+        options: QueryOptions,
     ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
         let resolver: Self = self.clone();
+        let slot = self.pick();
         Box::new(TcpResponse {
             resolver,
+            slot,
             query,
+            options,
             deadline: Delay::new(Instant::now() + self.timeout),
             resp_future: None,
         })
     }
+
+    /// The state of the first connection which is not idle
+    fn connection_state(&self) -> Option<&'static str> {
+        let mut state = "not-connected";
+        for slot in self.slots.iter() {
+            match &*slot.state.read() {
+                Connected(_) => return Some("connected"),
+                Connecting(_) => state = "connecting",
+                NotConnected => {}
+            }
+        }
+        Some(state)
+    }
 }
 
 pub type SimpleTcpResolver = TcpResolver<SimpleTcpDnsStreamBuilder>;
 
-pub type TlsResolver = TcpResolver<TlsDnsStreamBuilder>;
-
 pub struct TcpResponse<B: TcpDnsStreamBuilder> {
     resolver: TcpResolver<B>,
+    slot: usize,
     query: Query,
+    options: QueryOptions,
     deadline: Delay,
     resp_future: Option<OneshotDnsResponseReceiver<DnsMultiplexerSerialResponse>>,
 }
@@ -183,6 +485,7 @@ impl<B: TcpDnsStreamBuilder> Future for TcpResponse<B> {
     type Error = ProtoError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let slot = &self.resolver.slots[self.slot];
         match self.deadline.poll() {
             Ok(Async::Ready(_)) => {
                 // put unfinished task to background
@@ -191,10 +494,10 @@ impl<B: TcpDnsStreamBuilder> Future for TcpResponse<B> {
                 }
                 // Timeout indicates a connection is actually closed.
                 // (maybe no, but anyway we don't care)
-                let state = self.resolver.state.read();
+                let state = slot.state.read();
                 if let Connected(_) = &*state {
                     drop(state);
-                    *self.resolver.state.write() = NotConnected;
+                    slot.close();
                 }
                 return Err(ProtoErrorKind::Timeout.into());
             }
@@ -209,6 +512,10 @@ impl<B: TcpDnsStreamBuilder> Future for TcpResponse<B> {
         {
             Some(Ok(Async::Ready(resp))) => {
                 debug!(STDERR, "TcpResponse ready.");
+                *slot.last_used.lock() = Instant::now();
+                if self.resolver.options.keepalive {
+                    self.resolver.record_keepalive(self.slot, &resp);
+                }
                 Ok(Async::Ready(resp))
             }
             Some(Ok(Async::NotReady)) => {
@@ -216,7 +523,7 @@ impl<B: TcpDnsStreamBuilder> Future for TcpResponse<B> {
                 Ok(Async::NotReady)
             }
             Some(Err(e)) => {
-                let state = self.resolver.state.read();
+                let state = slot.state.read();
                 match &*state {
                     Connecting(_) => {
                         drop(state);
@@ -225,31 +532,37 @@ impl<B: TcpDnsStreamBuilder> Future for TcpResponse<B> {
                                 "Lookup error occurrs when connection is not established. Reset connection. {}", e
                             );
                         self.resp_future = None;
-                        *self.resolver.state.write() = NotConnected;
+                        slot.close();
                         task::current().notify();
                         Ok(Async::NotReady)
                     }
                     _ => {
-                        error!(STDERR, "Lookup error: {}. Will retry.", e);
+                        // Retries are up to the upstream options
                         self.resp_future = None;
-                        task::current().notify();
-                        Ok(Async::NotReady)
+                        Err(e)
                     }
                 }
             }
             None => {
-                let mut state = self.resolver.state.read();
+                let mut state = slot.state.read();
                 match &*state {
                     NotConnected => {
                         debug!(STDERR, "Not connected. Try to connect.");
                         RwLockReadGuard::unlocked(&mut state, || {
-                            self.resolver.connect();
+                            self.resolver.connect(self.slot);
                         });
+                        task::current().notify();
                         Ok(Async::NotReady)
                     }
                     Connecting(handle) | Connected(handle) => {
                         let mut resp_future =
-                            handle.clone().lookup(self.query.clone(), DNS_OPTIONS);
+                            if self.resolver.options.keepalive || !self.options.is_default() {
+                                handle
+                                    .clone()
+                                    .send(self.resolver.request(&self.query, &self.options))
+                            } else {
+                                handle.clone().lookup(self.query.clone(), DNS_OPTIONS)
+                            };
                         match resp_future.poll() {
                             Ok(Async::Ready(resp)) => {
                                 warn!(STDERR, "Immediately ready. Really?");
@@ -269,7 +582,7 @@ impl<B: TcpDnsStreamBuilder> Future for TcpResponse<B> {
                                     e.backtrace()
                                 );
                                 self.resp_future = None;
-                                *self.resolver.state.write() = NotConnected;
+                                slot.close();
                                 task::current().notify();
                                 Ok(Async::NotReady)
                             }
@@ -281,26 +594,6 @@ impl<B: TcpDnsStreamBuilder> Future for TcpResponse<B> {
     }
 }
 
-#[cfg(any(
-    target_os = "macos",
-    target_os = "windows",
-    target_arch = "mips",
-    target_arch = "mips64",
-    all(target_os = "freebsd", target_arch = "x86")
-))]
-#[path = "./tls/native.rs"]
-mod tls;
-
-#[cfg(not(any(
-    target_os = "macos",
-    target_os = "windows",
-    target_arch = "mips",
-    target_arch = "mips64",
-    all(target_os = "freebsd", target_arch = "x86")
-)))]
-#[path = "./tls/rustls.rs"]
-mod tls;
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -355,7 +648,10 @@ mod tests {
     }
 
     #[test]
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
     fn query_sync_tls() {
+        use crate::resolver::tls::{TlsDnsStreamBuilder, TlsResolver};
+
         let mut runtime = Runtime::new().expect("Unable to create a tokio runtime");
         let expected: IpAddr = [1, 1, 1, 1].into();
         let resolver: TlsResolver = runtime
@@ -398,4 +694,43 @@ mod tests {
             .flat_map(|record| record.rdata().to_ip_addr())
             .any(|ip| ip == expected));
     }
+
+    #[test]
+    fn no_reconnect_after_drop() {
+        use std::net::TcpListener;
+
+        // Accepts connections and closes them at once
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                drop(stream);
+            }
+        });
+
+        let mut runtime = Runtime::new().expect("Unable to create a tokio runtime");
+        let options = PoolOptions {
+            connections: 2,
+            reconnect: true,
+            ..PoolOptions::default()
+        };
+        let resolver = SimpleTcpResolver::with_options(
+            SimpleTcpDnsStreamBuilder::new(addr),
+            Duration::from_millis(500),
+            Duration::from_millis(500),
+            options,
+        );
+        let query = Query::query(Name::from_str("example.com.").unwrap(), RecordType::A);
+        let _ = runtime.block_on(future::lazy(move || resolver.query(query)));
+        assert!(accepted.load(Ordering::SeqCst) > 0);
+
+        // Let the pending reconnect delays expire
+        thread::sleep(Duration::from_secs(RECONNECT_DELAY_SECS * 2));
+        let count = accepted.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_secs(RECONNECT_DELAY_SECS * 3));
+        assert_eq!(accepted.load(Ordering::SeqCst), count);
+    }
 }
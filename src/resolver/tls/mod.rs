@@ -0,0 +1,96 @@
+//! DNS over TLS. The backend is selected by the `rustls` or `native-tls` feature.
+//! Without either of them, TLS upstreams are rejected when the dispatcher is built.
+
+use std::net::SocketAddr;
+
+use crate::resolver::connect::{Connector, Endpoint};
+use crate::resolver::tcp::TcpResolver;
+
+use failure::{err_msg, Error};
+
+#[cfg(feature = "rustls")]
+#[path = "rustls.rs"]
+mod backend;
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+#[path = "native.rs"]
+mod backend;
+
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+#[path = "none.rs"]
+mod backend;
+
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+mod x509;
+
+pub type TlsResolver = TcpResolver<TlsDnsStreamBuilder>;
+
+#[derive(Clone, Debug)]
+pub struct TlsDnsStreamBuilder {
+    connector: Connector,
+    /// The name sent in SNI
+    host: String,
+    config: backend::TlsConfig,
+}
+
+impl TlsDnsStreamBuilder {
+    /// Panics if TLS is not supported by this build
+    pub fn new(name_server: SocketAddr, host: String) -> Self {
+        let connector = Connector::new(Endpoint::Addr(name_server));
+        Self::with_options(connector, host, &TlsOptions::default())
+            .expect("Default TLS options are always valid")
+    }
+
+    /// Loads the files referred to by the options
+    pub fn with_options(
+        connector: Connector,
+        host: String,
+        options: &TlsOptions,
+    ) -> Result<Self, Error> {
+        if options.client_cert.is_some() != options.client_key.is_some() {
+            return Err(err_msg("client-cert and client-key must be set together"));
+        }
+        let config = backend::TlsConfig::new(&host, options)?;
+        let host = match &options.sni {
+            Sni::Name(name) => name.clone(),
+            _ => host,
+        };
+        Ok(TlsDnsStreamBuilder {
+            connector,
+            host,
+            config,
+        })
+    }
+}
+
+/// Settings of the TLS connections to an upstream
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// A PEM file of the CAs trusted instead of the built-in roots
+    pub ca_file: Option<String>,
+    /// Base64 encoded SHA-256 digests of the SubjectPublicKeyInfo.
+    /// The end-entity certificate, or a certificate it chains up to, must match one of them.
+    /// Only the end-entity certificate is checked if verification is skipped.
+    pub spki_pins: Vec<String>,
+    /// PEM files of the client certificate chain and its private key
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub sni: Sni,
+    /// Accepts any certificate. The SPKI pins are still checked.
+    pub insecure_skip_verify: bool,
+}
+
+/// The server name sent in the TLS handshake.
+/// The certificate is always verified against the TLS host.
+#[derive(Debug, Clone)]
+pub enum Sni {
+    Host,
+    Name(String),
+    Disabled,
+}
+
+impl Default for Sni {
+    fn default() -> Self {
+        Sni::Host
+    }
+}
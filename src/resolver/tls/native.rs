@@ -1,19 +1,187 @@
-use super::*;
+use super::x509;
+use super::{Sni, TlsDnsStreamBuilder, TlsOptions};
 
-use trust_dns_native_tls::TlsClientStream;
-use trust_dns_native_tls::TlsClientStreamBuilder;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::resolver::connect::with_timeout;
+use crate::resolver::tcp::{client_stream, TcpDnsStreamBuilder};
+use crate::Transpose;
+
+use failure::{err_msg, Error};
+use native_tls_crate::{Certificate, Identity};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio_tls::{TlsConnector, TlsStream};
+use trust_dns::client::ClientStreamHandle;
+use trust_dns::tcp::TcpClientStream;
+use trust_dns_proto::error::ProtoError;
+
+#[derive(Clone)]
+pub struct TlsConfig {
+    connector: TlsConnector,
+    checks: Arc<Checks>,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TlsConfig")
+    }
+}
+
+/// What native-tls cannot verify by itself, checked after the handshake
+struct Checks {
+    /// Verifies the certificate against this host when another name is sent in SNI
+    host: Option<String>,
+    pins: Vec<Vec<u8>>,
+}
+
+impl Checks {
+    /// native-tls only exposes the end-entity certificate,
+    /// so it is the only one the pins are checked against
+    fn verify(&self, cert: &[u8]) -> Result<(), String> {
+        if let Some(host) = &self.host {
+            if !x509::matches_host(cert, host) {
+                return Err(format!("The certificate is not valid for {}", host));
+            }
+        }
+        if !self.pins.is_empty() && !x509::matches_pins(cert, &self.pins) {
+            return Err("No certificate matches the SPKI pins".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl TlsConfig {
+    pub fn new(host: &str, options: &TlsOptions) -> Result<Self, Error> {
+        let mut builder = native_tls_crate::TlsConnector::builder();
+        if let Some(path) = &options.ca_file {
+            let certs = pem_blocks(&read(path)?, "CERTIFICATE");
+            if certs.is_empty() {
+                return Err(err_msg(format!("No valid CA certificate in {}", path)));
+            }
+            for pem in certs {
+                let ca = Certificate::from_pem(pem.as_bytes())
+                    .map_err(|e| err_msg(format!("Invalid CA file {}: {}", path, e)))?;
+                builder.add_root_certificate(ca);
+            }
+            builder.disable_built_in_roots(true);
+        }
+
+        if let (Some(cert), Some(key)) = (&options.client_cert, &options.client_key) {
+            // Only PKCS #8 keys are accepted by native-tls
+            let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?)
+                .map_err(|e| err_msg(format!("Invalid client certificate {}: {}", cert, e)))?;
+            builder.identity(identity);
+        }
+
+        builder.danger_accept_invalid_certs(options.insecure_skip_verify);
+        let host = match &options.sni {
+            Sni::Name(_) if !options.insecure_skip_verify => {
+                // The connection is made with the SNI name, which native-tls would verify
+                builder.danger_accept_invalid_hostnames(true);
+                Some(host.to_owned())
+            }
+            Sni::Disabled => {
+                builder.use_sni(false);
+                None
+            }
+            _ => None,
+        };
+
+        let checks = Checks {
+            host,
+            pins: x509::decode_pins(&options.spki_pins)?,
+        };
+        Ok(TlsConfig {
+            connector: TlsConnector::from(builder.build()?),
+            checks: Arc::new(checks),
+        })
+    }
+}
+
+fn read(path: &str) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Splits a PEM file into its blocks of the given label
+fn pem_blocks(pem: &[u8], label: &str) -> Vec<String> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let pem = String::from_utf8_lossy(pem);
+    let mut blocks = Vec::new();
+    let mut rest = &pem[..];
+    while let Some(start) = rest.find(&begin) {
+        match rest[start..].find(&end) {
+            Some(len) => {
+                let stop = start + len + end.len();
+                blocks.push(rest[start..stop].to_owned());
+                rest = &rest[stop..];
+            }
+            None => break,
+        }
+    }
+    blocks
+}
 
 impl TcpDnsStreamBuilder for TlsDnsStreamBuilder {
-    type Connect = Box<dyn Future<Item = TlsClientStream, Error = ProtoError> + Send>;
-    type Stream = TlsClientStream;
+    type Connect = Box<dyn Future<Item = Self::Stream, Error = ProtoError> + Send>;
+    type Stream = TcpClientStream<TlsStream<TcpStream>>;
+
+    fn name(&self) -> String {
+        self.connector.name()
+    }
 
     fn with_timeout(
         &self,
         timeout: Duration,
     ) -> (Self::Connect, Box<dyn ClientStreamHandle + 'static + Send>) {
-        // TODO Timeout is ignored because TlsClientStreamBuilder does not support a timeout
-        let (stream, handle) =
-            TlsClientStreamBuilder::new().build(self.name_server, self.host.clone());
-        (stream, Box::new(handle))
+        let tls = self.config.connector.clone();
+        let checks = self.config.checks.clone();
+        let host = self.host.clone();
+        let connect = self
+            .connector
+            .connect(timeout)
+            .and_then(move |stream| {
+                tls.connect(&host, stream)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            })
+            .and_then(move |stream| {
+                if checks.host.is_some() || !checks.pins.is_empty() {
+                    let cert = stream
+                        .get_ref()
+                        .peer_certificate()
+                        .and_then(|cert| Transpose::transpose(cert.map(|cert| cert.to_der())))
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    let cert = cert.ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::Other, "No server certificate")
+                    })?;
+                    checks
+                        .verify(&cert)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                }
+                Ok(stream)
+            });
+        client_stream(with_timeout(connect, timeout), self.connector.addr_hint())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_pem_blocks() {
+        let pem = b"junk\n-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+                    -----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+        let blocks = pem_blocks(pem, "CERTIFICATE");
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks[1].contains("BBBB"));
+        assert!(pem_blocks(pem, "PRIVATE KEY").is_empty());
     }
 }
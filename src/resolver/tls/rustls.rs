@@ -1,32 +1,283 @@
-use super::*;
-
-use lazy_static::lazy_static;
-use rustls::ClientConfig;
-use trust_dns_rustls::TlsClientStream;
-use trust_dns_rustls::TlsClientStreamBuilder;
-
-lazy_static! {
-    static ref RUSTLS_CONFIG: ClientConfig = {
-        let mut config = rustls::ClientConfig::new();
-        config
-            .root_store
-            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-        config
+use super::x509;
+use super::{Sni, TlsDnsStreamBuilder, TlsOptions};
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::resolver::connect::with_timeout;
+use crate::resolver::tcp::{client_stream, TcpDnsStreamBuilder};
+
+use failure::{err_msg, Error};
+use rustls_crate::internal::pemfile;
+use rustls_crate::{
+    Certificate, ClientConfig, ClientSession, PrivateKey, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, TLSError, WebPKIVerifier,
+};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio_rustls::{TlsConnector, TlsStream};
+use trust_dns::client::ClientStreamHandle;
+use trust_dns::tcp::TcpClientStream;
+use trust_dns_proto::error::ProtoError;
+use untrusted::Input;
+use webpki::{trust_anchor_util, DNSNameRef, EndEntityCert, TLSServerTrustAnchors};
+
+#[derive(Clone)]
+pub struct TlsConfig(Arc<ClientConfig>);
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TlsConfig")
+    }
+}
+
+impl TlsConfig {
+    pub fn new(host: &str, options: &TlsOptions) -> Result<Self, Error> {
+        let names = match &options.sni {
+            Sni::Name(name) => vec![host, name.as_str()],
+            _ => vec![host],
+        };
+        if let Some(name) = names
+            .into_iter()
+            .find(|name| DNSNameRef::try_from_ascii_str(name).is_err())
+        {
+            return Err(err_msg(format!("Invalid TLS host: {}", name)));
+        }
+
+        let mut config = ClientConfig::new();
+        match &options.ca_file {
+            Some(path) => {
+                let mut reader = BufReader::new(File::open(path)?);
+                let (valid, _) = config
+                    .root_store
+                    .add_pem_file(&mut reader)
+                    .map_err(|_| err_msg(format!("Invalid CA file: {}", path)))?;
+                if valid == 0 {
+                    return Err(err_msg(format!("No valid CA certificate in {}", path)));
+                }
+            }
+            None => config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        }
+
+        if let (Some(cert), Some(key)) = (&options.client_cert, &options.client_key) {
+            config.set_single_client_cert(load_certs(cert)?, load_key(key)?);
+        }
+
+        if let Sni::Disabled = options.sni {
+            config.enable_sni = false;
+        }
+
+        let pins = x509::decode_pins(&options.spki_pins)?;
+
+        // The default verifier checks the certificate against the name sent in SNI
+        let sni_differs = match options.sni {
+            Sni::Host => false,
+            _ => true,
+        };
+        if sni_differs || options.insecure_skip_verify || !pins.is_empty() {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(Verifier {
+                    webpki: WebPKIVerifier::new(),
+                    host: host.to_owned(),
+                    pins,
+                    insecure: options.insecure_skip_verify,
+                }));
+        }
+        Ok(TlsConfig(Arc::new(config)))
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, Error> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| err_msg(format!("Invalid certificate file: {}", path)))?;
+    if certs.is_empty() {
+        return Err(err_msg(format!("No certificate in {}", path)));
+    }
+    Ok(certs)
+}
+
+/// Accepts both PKCS #8 and RSA private keys
+fn load_key(path: &str) -> Result<PrivateKey, Error> {
+    let invalid = |_| err_msg(format!("Invalid private key file: {}", path));
+    let mut keys =
+        pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?)).map_err(invalid)?;
+    if keys.is_empty() {
+        keys =
+            pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?)).map_err(invalid)?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| err_msg(format!("No private key in {}", path)))
+}
+
+/// Verifies the certificate against the TLS host even if another name is sent in SNI,
+/// and checks the SPKI pins
+struct Verifier {
+    webpki: WebPKIVerifier,
+    host: String,
+    pins: Vec<Vec<u8>>,
+    insecure: bool,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        if !self.insecure {
+            let host = DNSNameRef::try_from_ascii_str(&self.host)
+                .map_err(|_| TLSError::General(format!("Invalid TLS host: {}", self.host)))?;
+            self.webpki
+                .verify_server_cert(roots, presented_certs, host, ocsp_response)?;
+        }
+        if !self.pins.is_empty() && !self.pinned(presented_certs) {
+            return Err(TLSError::General(
+                "No certificate matches the SPKI pins".to_string(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl Verifier {
+    /// Tells if a pinned certificate is on the validated path of the chain.
+    /// Without verification, only the end-entity certificate is known to belong to the server.
+    fn pinned(&self, presented_certs: &[Certificate]) -> bool {
+        let matches = |cert: &Certificate| x509::matches_pins(&cert.0, &self.pins);
+        let (end_entity, intermediates) = match presented_certs.split_first() {
+            Some(certs) => certs,
+            None => return false,
+        };
+        if matches(end_entity) {
+            return true;
+        }
+        if self.insecure {
+            return false;
+        }
+        // Anything can be appended to the chain, so an intermediate only counts
+        // if the end-entity certificate chains up to it
+        intermediates
+            .iter()
+            .filter(|&cert| matches(cert))
+            .any(|cert| issued_by(end_entity, intermediates, cert))
+    }
+}
+
+/// The signature algorithms accepted by rustls
+static SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Tells if `end_entity` is valid with `issuer` as the trust anchor,
+/// through the other certificates of the chain
+fn issued_by(
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    issuer: &Certificate,
+) -> bool {
+    let anchor = match trust_anchor_util::cert_der_as_trust_anchor(Input::from(&issuer.0)) {
+        Ok(anchor) => anchor,
+        Err(_) => return false,
+    };
+    let cert = match EndEntityCert::from(Input::from(&end_entity.0)) {
+        Ok(cert) => cert,
+        Err(_) => return false,
     };
+    let time = match webpki::Time::try_from(SystemTime::now()) {
+        Ok(time) => time,
+        Err(_) => return false,
+    };
+    let intermediates: Vec<_> = intermediates
+        .iter()
+        .map(|cert| Input::from(&cert.0))
+        .collect();
+    cert.verify_is_valid_tls_server_cert(
+        SIG_ALGS,
+        &TLSServerTrustAnchors(&[anchor]),
+        &intermediates,
+        time,
+    )
+    .is_ok()
 }
 
 impl TcpDnsStreamBuilder for TlsDnsStreamBuilder {
-    type Connect = Box<dyn Future<Item = TlsClientStream, Error = ProtoError> + Send>;
-    type Stream = TlsClientStream;
+    type Connect = Box<dyn Future<Item = Self::Stream, Error = ProtoError> + Send>;
+    type Stream = TcpClientStream<TlsStream<TcpStream, ClientSession>>;
+
+    fn name(&self) -> String {
+        self.connector.name()
+    }
 
     fn with_timeout(
         &self,
         timeout: Duration,
     ) -> (Self::Connect, Box<dyn ClientStreamHandle + 'static + Send>) {
-        // TODO: Timeout is ignored because TlsClientStreamBuilder does not support a timeout
-        // TODO: Avoid cloning: https://github.com/bluejekyll/trust-dns/issues/618
-        let (stream, handle) = TlsClientStreamBuilder::with_client_config(RUSTLS_CONFIG.clone())
-            .build(self.name_server, self.host.clone());
-        (stream, Box::new(handle))
+        let tls = TlsConnector::from(self.config.0.clone());
+        let host = self.host.clone();
+        let connect = self.connector.connect(timeout).and_then(move |stream| {
+            // Checked when loading the options
+            let name = DNSNameRef::try_from_ascii_str(&host).expect("Invalid TLS host");
+            tls.connect(name, stream)
+        });
+        client_stream(with_timeout(connect, timeout), self.connector.addr_hint())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pin(cert: &[u8]) -> Vec<u8> {
+        x509::sha256(x509::spki(cert).unwrap())
+    }
+
+    #[test]
+    fn pins_on_validated_path() {
+        let leaf = Certificate(include_bytes!("testdata/leaf.der").to_vec());
+        let ca = Certificate(include_bytes!("testdata/ca.der").to_vec());
+        let other = Certificate(include_bytes!("testdata/other.der").to_vec());
+        let mut roots = RootCertStore::empty();
+        roots.add(&ca).unwrap();
+        let verify = |pins: Vec<Vec<u8>>, insecure: bool, chain: &[Certificate]| {
+            let verifier = Verifier {
+                webpki: WebPKIVerifier::new(),
+                host: "dns.example".to_string(),
+                pins,
+                insecure,
+            };
+            let name = DNSNameRef::try_from_ascii_str("dns.example").unwrap();
+            verifier
+                .verify_server_cert(&roots, chain, name, &[])
+                .is_ok()
+        };
+
+        let chain = [leaf.clone(), ca.clone()];
+        assert!(verify(vec![pin(&leaf.0)], false, &chain));
+        assert!(verify(vec![pin(&ca.0)], false, &chain));
+        assert!(!verify(vec![pin(&ca.0)], true, &chain));
+
+        // A pinned certificate appended to a valid chain
+        let chain = [leaf.clone(), ca.clone(), other.clone()];
+        assert!(!verify(vec![pin(&other.0)], false, &chain));
+        assert!(!verify(vec![pin(&other.0)], true, &chain));
     }
 }
@@ -0,0 +1,174 @@
+//! Just enough X.509 parsing for the checks the TLS backends leave to us
+
+use failure::{err_msg, Error};
+
+/// Decodes the base64 encoded SHA-256 digests of the `spki-pins` option
+pub fn decode_pins(pins: &[String]) -> Result<Vec<Vec<u8>>, Error> {
+    pins.iter()
+        .map(|pin| match base64::decode(pin) {
+            Ok(ref digest) if digest.len() == 32 => Ok(digest.clone()),
+            _ => Err(err_msg(format!("Invalid SPKI pin: {}", pin))),
+        })
+        .collect()
+}
+
+/// Tells if the SubjectPublicKeyInfo of the DER encoded certificate matches one of the pins
+pub fn matches_pins(cert: &[u8], pins: &[Vec<u8>]) -> bool {
+    spki(cert).map_or(false, |spki| {
+        let digest = sha256(spki);
+        pins.iter().any(|pin| *pin == digest)
+    })
+}
+
+/// Hashed with the crypto library the TLS backend already depends on
+#[cfg(feature = "rustls")]
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    ring::digest::digest(&ring::digest::SHA256, data)
+        .as_ref()
+        .to_vec()
+}
+
+#[cfg(not(feature = "rustls"))]
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    use sha2::Digest;
+    sha2::Sha256::digest(data).to_vec()
+}
+
+/// Tells if the subjectAltName of the DER encoded certificate covers `host`.
+/// A wildcard stands for exactly one whole label, like `*.example.com`.
+pub fn matches_host(cert: &[u8], host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    dns_names(cert).unwrap_or_default().into_iter().any(|name| {
+        let name = String::from_utf8_lossy(name);
+        let name = name.trim_end_matches('.');
+        if name.starts_with("*.") {
+            match host.find('.') {
+                Some(i) => i > 0 && host[i + 1..].eq_ignore_ascii_case(&name[2..]),
+                None => false,
+            }
+        } else {
+            host.eq_ignore_ascii_case(name)
+        }
+    })
+}
+
+/// The fields of TBSCertificate following the version
+struct TbsFields<'a> {
+    spki: &'a [u8],
+    /// Whatever follows subjectPublicKeyInfo
+    rest: &'a [u8],
+}
+
+fn tbs_fields(cert: &[u8]) -> Option<TbsFields> {
+    let (_, cert, _) = der_element(cert)?;
+    let (_, tbs, _) = der_element(cert)?;
+    let mut rest = tbs;
+    // The optional version tagged [0]
+    if rest.first() == Some(&0xa0) {
+        rest = der_element(rest)?.2;
+    }
+    // serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.2;
+    }
+    let (spki, _, rest) = der_element(rest)?;
+    Some(TbsFields { spki, rest })
+}
+
+/// Returns the DER encoded SubjectPublicKeyInfo of an X.509 certificate
+pub fn spki(cert: &[u8]) -> Option<&[u8]> {
+    tbs_fields(cert).map(|fields| fields.spki)
+}
+
+/// Returns the dNSName entries of the subjectAltName extension
+fn dns_names(cert: &[u8]) -> Option<Vec<&[u8]>> {
+    const SUBJECT_ALT_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+
+    let mut rest = tbs_fields(cert)?.rest;
+    // Skip issuerUniqueID [1] and subjectUniqueID [2]
+    while !rest.is_empty() && rest[0] != 0xa3 {
+        rest = der_element(rest)?.2;
+    }
+    let (_, extensions, _) = der_element(rest)?;
+    let (_, mut extensions, _) = der_element(extensions)?;
+    while !extensions.is_empty() {
+        let (_, extension, next) = der_element(extensions)?;
+        extensions = next;
+        let (oid, _, mut value) = der_element(extension)?;
+        if oid != SUBJECT_ALT_NAME {
+            continue;
+        }
+        // The optional critical flag
+        if value.first() == Some(&0x01) {
+            value = der_element(value)?.2;
+        }
+        let (_, value, _) = der_element(value)?;
+        let (_, mut general_names, _) = der_element(value)?;
+        let mut names = Vec::new();
+        while !general_names.is_empty() {
+            let (name, content, next) = der_element(general_names)?;
+            general_names = next;
+            // dNSName is tagged [2]
+            if name[0] == 0x82 {
+                names.push(content);
+            }
+        }
+        return Some(names);
+    }
+    Some(Vec::new())
+}
+
+/// Splits off the first DER element, returning the whole element, its content and the rest
+fn der_element(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *input.get(1)?;
+    let (len, header) = if first & 0x80 == 0 {
+        (first as usize, 2)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + n)?
+            .iter()
+            .fold(0, |len, b| len << 8 | *b as usize);
+        (len, 2 + n)
+    };
+    let end = header.checked_add(len)?;
+    if input.len() < end {
+        return None;
+    }
+    Some((&input[..end], &input[header..end], &input[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spki_of_certificate() {
+        let expected = [0x30, 0x03, 0x06, 0x01, 0x2a];
+        let mut tbs = vec![
+            0xa0, 0x03, 0x02, 0x01, 0x02, // version
+            0x02, 0x01, 0x01, // serialNumber
+            0x30, 0x00, // signature
+            0x30, 0x00, // issuer
+            0x30, 0x00, // validity
+            0x30, 0x00, // subject
+        ];
+        tbs.extend_from_slice(&expected);
+        let mut cert = vec![0x30, tbs.len() as u8 + 2, 0x30, tbs.len() as u8];
+        cert.extend_from_slice(&tbs);
+        assert_eq!(spki(&cert), Some(&expected[..]));
+        assert_eq!(spki(&cert[..cert.len() - 1]), None);
+    }
+
+    #[test]
+    fn host_of_certificate() {
+        let cert = include_bytes!("testdata/leaf.der");
+        assert!(matches_host(cert, "dns.example"));
+        assert!(matches_host(cert, "DNS.example."));
+        assert!(!matches_host(cert, "other.example"));
+        assert!(!matches_host(cert, "a.dns.example"));
+    }
+}
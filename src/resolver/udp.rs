@@ -1,21 +1,33 @@
 use super::*;
 
+use std::fmt;
+use std::io;
 use std::net::SocketAddr;
 use std::time::Duration;
 
+use crate::metrics;
+use crate::resolver::connect::{Connector, Endpoint};
+use crate::resolver::socket::SocketOptions;
+use crate::resolver::tcp::{SimpleTcpDnsStreamBuilder, SimpleTcpResolver};
 use crate::STDERR;
 
-use slog::debug;
+use futures::try_ready;
+use slog::{debug, warn};
 use trust_dns::client::BasicClientHandle;
 use trust_dns::client::ClientFuture;
 use trust_dns::op::Query;
 use trust_dns::udp::UdpClientStream;
+use trust_dns_proto::udp::UdpStream;
 use trust_dns_proto::xfer::dns_handle::DnsHandle;
 use trust_dns_proto::xfer::dns_multiplexer::DnsMultiplexerSerialResponse;
+use trust_dns_proto::xfer::{BufDnsStreamHandle, DnsClientStream, SerialMessage};
 
+/// Queries over UDP. Truncated responses are retried over TCP to the same address.
 #[derive(Clone)]
 pub struct SimpleUdpResolver {
+    server_addr: SocketAddr,
     handle: BasicClientHandle<DnsMultiplexerSerialResponse>,
+    fallback: SimpleTcpResolver,
 }
 
 impl SimpleUdpResolver {
@@ -24,23 +36,140 @@ impl SimpleUdpResolver {
     }
 
     pub fn with_timeout(server_addr: SocketAddr, timeout: Duration) -> Self {
-        let (stream, handle) = UdpClientStream::new(server_addr);
-        let (bg, handle) = ClientFuture::with_timeout(stream, handle, timeout, None);
+        Self::with_options(server_addr, timeout, SocketOptions::default())
+            .expect("Unable to create a UDP socket")
+    }
+
+    /// The TCP fallback uses the same socket options
+    pub fn with_options(
+        server_addr: SocketAddr,
+        timeout: Duration,
+        socket: SocketOptions,
+    ) -> io::Result<Self> {
+        // Spawned in each branch because the background futures differ in type
+        let handle = if socket.is_default() {
+            let (stream, handle) = UdpClientStream::new(server_addr);
+            let (bg, handle) = ClientFuture::with_timeout(stream, handle, timeout, None);
+            tokio::spawn(bg);
+            handle
+        } else {
+            let (stream, handle) = UdpStream::with_bound(socket.bind_udp(&server_addr)?);
+            let stream = BoundUdpClientStream {
+                name_server: server_addr,
+                stream,
+            };
+            let handle = BufDnsStreamHandle::new(server_addr, handle);
+            let (bg, handle) = ClientFuture::with_timeout(
+                Box::new(future::ok::<_, ProtoError>(stream)),
+                Box::new(handle),
+                timeout,
+                None,
+            );
+            tokio::spawn(bg);
+            handle
+        };
         debug!(
             STDERR,
             "SimpleUdpResolver initialized. DNS requests are forwarded to {}.", server_addr
         );
-        tokio::spawn(bg);
-        SimpleUdpResolver { handle }
+        // No connection is made until the first fallback
+        let fallback = SimpleTcpResolver::with_timeout(
+            SimpleTcpDnsStreamBuilder::with_connector(
+                Connector::new(Endpoint::Addr(server_addr)).with_socket_options(socket),
+            ),
+            timeout,
+        );
+        Ok(SimpleUdpResolver {
+            server_addr,
+            handle,
+            fallback,
+        })
+    }
+
+    fn needs_tcp(resp: &DnsResponse) -> bool {
+        resp.truncated()
+            || resp
+                .to_vec()
+                .map(|bytes| bytes.len() > usize::from(MAX_PAYLOAD_LEN))
+                .unwrap_or(false)
     }
 }
 
 impl Resolver for SimpleUdpResolver {
-    fn query(
+    fn query_with(
         &self,
         query: Query,
+        options: QueryOptions,
     ) -> Box<Future<Item = DnsResponse, Error = ProtoError> + 'static + Send> {
-        Box::new(self.handle.clone().lookup(query, DNS_OPTIONS))
+        let server_addr = self.server_addr;
+        let fallback = self.fallback.clone();
+        let response = if options.is_default() {
+            self.handle.clone().lookup(query.clone(), DNS_OPTIONS)
+        } else {
+            self.handle
+                .clone()
+                .send(request(&query, &options, Vec::new()))
+        };
+        Box::new(response.and_then(move |resp| {
+            if !Self::needs_tcp(&resp) {
+                return Box::new(future::ok(resp))
+                    as Box<Future<Item = DnsResponse, Error = ProtoError> + Send>;
+            }
+            debug!(
+                STDERR,
+                "Response from {} is truncated. Retry over TCP.", server_addr
+            );
+            metrics::TCP_FALLBACKS
+                .with_label_values(&[&server_addr.to_string()])
+                .inc();
+            Box::new(fallback.query_with(query, options).or_else(move |e| {
+                warn!(
+                    STDERR,
+                    "TCP fallback to {} failed: {}. Use the truncated response.", server_addr, e
+                );
+                Ok(resp)
+            }))
+        }))
+    }
+}
+
+/// Receives the responses on a socket created with `SocketOptions`.
+/// Messages not from the name server are dropped.
+struct BoundUdpClientStream {
+    name_server: SocketAddr,
+    stream: UdpStream,
+}
+
+impl fmt::Display for BoundUdpClientStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UDP({})", self.name_server)
+    }
+}
+
+impl Stream for BoundUdpClientStream {
+    type Item = SerialMessage;
+    type Error = ProtoError;
+
+    fn poll(&mut self) -> Poll<Option<SerialMessage>, ProtoError> {
+        loop {
+            match try_ready!(self.stream.poll()) {
+                Some(message) if message.addr() != self.name_server => {
+                    warn!(
+                        STDERR,
+                        "Dropped a UDP message from {} instead of {}",
+                        message.addr(),
+                        self.name_server
+                    );
+                }
+                message => return Ok(Async::Ready(message)),
+            }
+        }
+    }
+}
+
+impl DnsClientStream for BoundUdpClientStream {
+    fn name_server_addr(&self) -> SocketAddr {
+        self.name_server
     }
 }
 
@@ -51,8 +180,17 @@ mod tests {
     use std::str::FromStr;
     use std::thread;
     use tokio::runtime::Runtime;
+    use trust_dns::op::Message;
     use trust_dns::rr::{Name, RecordType};
 
+    #[test]
+    fn truncated_needs_tcp() {
+        let mut message = Message::new();
+        assert!(!SimpleUdpResolver::needs_tcp(&message.clone().into()));
+        message.set_truncated(true);
+        assert!(SimpleUdpResolver::needs_tcp(&message.into()));
+    }
+
     #[test]
     fn sync_query() {
         let mut runtime = Runtime::new().expect("Unable to create a tokio runtime");